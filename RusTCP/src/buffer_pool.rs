@@ -0,0 +1,40 @@
+// Reusable byte buffers, for every packet (or piece of one) that has to outlive the call that
+// built it, or be built on the heap in the first place: frames waiting out an artificial delay,
+// replies built a header at a time, out-of-order data held for reassembly. Each of those used
+// to allocate a Vec of its own and free it moments later; with the pool, a buffer handed back
+// is handed out again, and a steady stream of packets settles into reusing the same few.
+//
+// There's one pool per interface, kept by the Nic, which every connection sends through. It
+// only ever holds on to MAX_POOLED buffers: a burst that needed more than that (say, a whole
+// window held out of order) frees what it doesn't give back, rather than keeping it forever.
+pub(crate) struct BufferPool {
+    free: Vec<Vec<u8>>,
+}
+
+// Most buffers the pool keeps around for reuse
+const MAX_POOLED: usize = 256;
+
+// What a fresh buffer is sized for: a full packet on the TUN link, with the 4 byte frame prefix.
+// Buffers grow past that if they have to, and keep their size when they're reused
+const BUFFER_CAPACITY: usize = 1504;
+
+impl BufferPool {
+    pub(crate) fn new() -> Self {
+        BufferPool { free: Vec::new() }
+    }
+
+    // An empty buffer, from the pool if it has one, otherwise a new one
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        self.free
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUFFER_CAPACITY))
+    }
+
+    // Hands a buffer back for reuse. Its contents are gone from then on
+    pub(crate) fn give(&mut self, mut buf: Vec<u8>) {
+        if self.free.len() < MAX_POOLED {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+}
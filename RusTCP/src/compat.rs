@@ -0,0 +1,175 @@
+// Stand-ins for std::net::TcpListener and std::net::TcpStream, so that code written against
+// std can be moved onto this stack by changing its `use` line:
+//
+//     use rustcp::compat::{TcpListener, TcpStream};
+//
+// They cover the commonly used subset of the std API, with the same signatures. Behind them is
+// one process-wide Interface with the default configuration, brought up on first use. Code
+// that needs anything else from the stack (its configuration, metrics, ...) should use
+// Interface and friends directly instead.
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::sync::Mutex;
+
+use crate::{Interface, Listener};
+
+// The Interface every compat socket lives on, once it's been created. It's leaked so that
+// sockets can borrow it for the rest of the program: there's no point where we could drop it
+static INTERFACE: Mutex<Option<&'static Interface>> = Mutex::new(None);
+
+fn interface() -> io::Result<&'static Interface> {
+    let mut iface = INTERFACE.lock().unwrap();
+    if let Some(iface) = *iface {
+        return Ok(iface);
+    }
+    let new: &'static Interface = Box::leak(Box::new(Interface::new()?));
+    *iface = Some(new);
+    Ok(new)
+}
+
+// The first IPv4 address `addr` resolves to; the stack doesn't do IPv6
+fn resolve_v4(addr: impl ToSocketAddrs) -> io::Result<SocketAddrV4> {
+    addr.to_socket_addrs()?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no IPv4 address to use"))
+}
+
+// Like std::net::TcpListener
+pub struct TcpListener {
+    // Locked for the duration of an accept, since Listener::accept wants exclusive access
+    inner: Mutex<Listener>,
+    local_addr: SocketAddrV4,
+}
+
+impl TcpListener {
+    // Listens on the address's port. The address itself has to be one of the interface's, or
+    // the unspecified address (0.0.0.0) for all of them; either way connections to any of our
+    // addresses are accepted, the stack doesn't bind to addresses
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        let addr = resolve_v4(addr)?;
+        let iface = interface()?;
+        let ours = iface
+            .config()
+            .local_addrs
+            .iter()
+            .any(|(ip, _)| ip == addr.ip());
+        if !ours && !addr.ip().is_unspecified() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} is not one of our addresses", addr.ip()),
+            ));
+        }
+        Ok(TcpListener {
+            inner: Mutex::new(iface.bind(addr.port())?),
+            local_addr: addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::V4(self.local_addr))
+    }
+
+    // Blocks until a peer connects, and returns the new stream and the peer's address
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let stream = TcpStream {
+            inner: self.inner.lock().unwrap().accept()?,
+        };
+        let peer = stream.peer_addr()?;
+        Ok((stream, peer))
+    }
+
+    // Accepts connections forever, one per iteration
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+// Iterator over the connections accepted on a TcpListener, see TcpListener::incoming
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+// Like std::net::TcpStream
+pub struct TcpStream {
+    inner: crate::TcpStream,
+}
+
+impl TcpStream {
+    // Opens a connection to the first IPv4 address `addr` resolves to
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        let addr = resolve_v4(addr)?;
+        let inner = interface()?.connect((*addr.ip(), addr.port()))?;
+        Ok(TcpStream { inner })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let (ip, port) = self.inner.quad().source_socket;
+        Ok(socket_addr(ip, port))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let (ip, port) = self.inner.quad().destination_socket;
+        Ok(socket_addr(ip, port))
+    }
+
+    // See crate::TcpStream::shutdown for what shutting down each side does
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    // The stack doesn't implement Nagle's algorithm, so every stream already behaves as if
+    // TCP_NODELAY were set. Turning it off is accepted, but doesn't change anything
+    pub fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        Ok(TcpStream {
+            inner: self.inner.try_clone()?,
+        })
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    // The stream on the native API, for whatever isn't covered here
+    pub fn into_inner(self) -> crate::TcpStream {
+        self.inner
+    }
+}
+
+fn socket_addr(ip: Ipv4Addr, port: u16) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(ip, port))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
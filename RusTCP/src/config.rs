@@ -0,0 +1,270 @@
+use std::cmp;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+// Settings shared by every connection on an Interface, see Interface::with_config
+#[derive(Clone, Debug)]
+pub struct TcpConfig {
+    // Maximum Segment Lifetime: how long a segment may linger in the network (RFC 793 Sec. 3.3).
+    // TIME-WAIT lasts twice that, so every segment of the old connection is gone by the end of
+    // it. The default is RFC 793's two minutes; tests that don't want to wait out a four
+    // minute TIME-WAIT can make it much shorter
+    pub msl: Duration,
+    // What an in-window RST does to a connection in TIME-WAIT
+    pub time_wait_rst: TimeWaitRst,
+    // What a new SYN does to a connection in TIME-WAIT with the same quad
+    pub time_wait_syn: TimeWaitSyn,
+    // How many times the same segment is retransmitted before we give up on the peer and
+    // reset the connection (like Linux's tcp_retries2)
+    pub max_retransmits: u32,
+    // How many times our SYN is retransmitted before Interface::connect gives up with TimedOut
+    // (like Linux's tcp_syn_retries). With the RTO doubling from 1s, the default of 6 waits
+    // for the SYN-ACK for about two minutes in all
+    pub max_syn_retransmits: u32,
+    // Which congestion control algorithm new connections use, by its registered name (see
+    // congestion::algorithm_names)
+    pub congestion_control: String,
+    // Our addresses on the TUN device, each with the prefix length of its subnet. Connections
+    // we open are sourced from one of them, see Interface::connect. The first one is the
+    // fallback for destinations that aren't on any of the subnets
+    pub local_addrs: Vec<(Ipv4Addr, u8)>,
+    // The smallest MSS we go along with: a peer announcing less gets segments of this size
+    // anyway. The default is Linux's TCP_MIN_MSS, 88: a 128 byte packet with maximal IP and TCP
+    // headers (60 bytes each) leaves 8 bytes of payload, and 88 once the headers are minimal
+    pub min_peer_mss: usize,
+    // Path MTU black-hole detection. We send with DF set, relying on routers to say with an
+    // ICMP when a packet is too big for the next hop, but some paths drop such packets
+    // without a word (or the ICMP gets filtered). Then every full-sized segment is lost while
+    // small ones get through, and all we see is timeouts. After this many timeouts in a row
+    // with a full-sized segment outstanding, we assume that's what is going on and drop to
+    // segments of blackhole_mss bytes, like an ICMP had told us so (RFC 2923 Sec. 2.1). None,
+    // the default, leaves detection off
+    pub blackhole_rtos: Option<u32>,
+    // The segment size to fall back to once a black hole is detected. The default of 536 is
+    // what every IPv4 path has to carry (RFC 879)
+    pub blackhole_mss: usize,
+    // How much in-order data may arrive before we stop delaying its ACK. None means two
+    // full-sized segments, at the MSS negotiated with the peer (the smaller of its and ours)
+    pub delayed_ack_bytes: Option<usize>,
+    // Lets the ACK of the peer's FIN wait for the delayed ACK timer, like that of data. By
+    // default it goes out right away, since holding it back only keeps the peer in its closing
+    // states longer. Delaying it does save a segment when the application closes its side soon
+    // after reading the end of the stream: our FIN then carries the ACK
+    pub delay_fin_ack: bool,
+    // Check every retransmission timeout for whether it was spurious, and undo the congestion
+    // window reduction if so (F-RTO, RFC 5682)
+    pub frto: bool,
+    // Never advertise a receive window larger than this many bytes, whatever room the receive
+    // buffer has. Makes us behave like a receiver with a tiny buffer, to see how the peer
+    // copes with being flow controlled
+    pub max_advertised_window: Option<usize>,
+    // What happens to data the peer sends after we've shut down our reading side
+    pub read_shutdown: ReadShutdown,
+    // The send queue (data written and not yet acknowledged): writes block once it's at the
+    // high-water mark, and only carry on once it's drained back down to the low-water mark.
+    // Both default to 64KiB, so a blocked write goes on as soon as there's any room
+    pub send_buffer: BufferMarks,
+    // The receive buffer (data received and not yet read): the high-water mark is its size, and
+    // the window we advertise is whatever room is left below it. Reads wait until at least the
+    // low-water mark is buffered (or the peer has closed), like SO_RCVLOWAT. The defaults are
+    // 64KiB and 1 byte, so reads return whatever has arrived
+    pub recv_buffer: BufferMarks,
+    // Pay no attention to PSH. Normally data the peer pushed (sent with PSH) is handed to a read
+    // even while less than the receive buffer's low-water mark has arrived, since the sender
+    // said not to wait for more. With this set, only the low-water mark, the end of the stream
+    // and errors end a read's wait, for applications that want their data in big batches
+    // whatever the sender does
+    pub ignore_psh: bool,
+    // Probe a connection once it's been idle this long, to find out whether the peer is still
+    // there (RFC 1122 Sec. 4.2.3.6). None, the default, leaves keep-alives off, as RFC 1122 has it
+    pub keepalive_idle: Option<Duration>,
+    // Time between unanswered keep-alive probes, and how many may go unanswered before the
+    // connection is given up on (like Linux's tcp_keepalive_intvl and tcp_keepalive_probes)
+    pub keepalive_interval: Duration,
+    pub keepalive_probes: u32,
+    // Whether keep-alive probes carry no data (0, the default) or one garbage byte (1, or
+    // anything more). Some middleboxes only count segments with data as activity. Either way the
+    // probe's sequence number is the one before SND.NXT, which the peer has already had, so it
+    // throws the byte away and just answers with an ACK
+    pub keepalive_probe_bytes: usize,
+    // How many of its most recent segments (sent and received) each connection records for
+    // TcpStream::sequence_diagram. 0, the default, records nothing
+    pub trace_segments: usize,
+    // Size of the buffer the packet loop reads each frame from the TUN device into: the
+    // largest packet the link carries plus the 4 byte frame prefix. The default fits the
+    // standard 1500 byte MTU; jumbo frames need more, up to READ_BUFFER_SIZES.end() for the
+    // largest IPv4 packet there is. A packet too big for the buffer is dropped
+    pub read_buffer_size: usize,
+    // How the IP Identification of the packets we send is picked, see IpIdStrategy
+    pub ip_id: IpIdStrategy,
+    // Where the urgent pointer of a segment with URG set says the urgent data ends, see
+    // UrgentPointer
+    pub urgent_pointer: UrgentPointer,
+    // Most challenge ACKs a connection sends a second, in answer to RSTs that land in the window
+    // but not on RCV.NXT. A blind attacker can spray such RSTs at us, and each would otherwise
+    // get an ACK: the limit keeps that from turning us into a flood of ACKs, and from telling a
+    // watching attacker how many of its guesses hit the window (RFC 5961 Sec. 7)
+    pub challenge_ack_limit: u32,
+    // Hand the TUN device several full-sized segments in one write, behind a single IP and TCP
+    // header, and have the kernel cut them up (GSO, generic segmentation offload): a bulk
+    // transfer then takes a write per 64KiB or so instead of one per segment. That takes the
+    // device opened with a virtio-net header on every frame (IFF_VNET_HDR), which the Interface
+    // does when this is set. If the kernel won't have it, or nothing is ready to go but a
+    // segment or less, segments go out one per write as they do without it
+    pub segmentation_offload: bool,
+}
+
+// The read buffer sizes Interface::with_config takes (see TcpConfig::read_buffer_size): at
+// least a standard MTU's worth, and at most a 65535 byte IPv4 packet with the frame prefix
+pub const READ_BUFFER_SIZES: std::ops::RangeInclusive<usize> = 1504..=65539;
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            msl: Duration::from_secs(120),
+            time_wait_rst: TimeWaitRst::default(),
+            time_wait_syn: TimeWaitSyn::default(),
+            max_retransmits: 15,
+            max_syn_retransmits: 6,
+            congestion_control: "reno".to_string(),
+            local_addrs: vec![(Ipv4Addr::new(192, 168, 0, 2), 24)],
+            min_peer_mss: 88,
+            blackhole_rtos: None,
+            blackhole_mss: 536,
+            delayed_ack_bytes: None,
+            delay_fin_ack: false,
+            frto: false,
+            max_advertised_window: None,
+            read_shutdown: ReadShutdown::default(),
+            send_buffer: BufferMarks {
+                low: 64 * 1024,
+                high: 64 * 1024,
+            },
+            recv_buffer: BufferMarks {
+                low: 1,
+                high: 64 * 1024,
+            },
+            ignore_psh: false,
+            keepalive_idle: None,
+            keepalive_interval: Duration::from_secs(75),
+            keepalive_probes: 9,
+            keepalive_probe_bytes: 0,
+            trace_segments: 0,
+            read_buffer_size: *READ_BUFFER_SIZES.start(),
+            ip_id: IpIdStrategy::default(),
+            urgent_pointer: UrgentPointer::default(),
+            challenge_ack_limit: 10,
+            segmentation_offload: false,
+        }
+    }
+}
+
+impl TcpConfig {
+    // Picks the source address for a connection to `remote`: the one of our addresses whose
+    // subnet contains it (the most specific subnet if several do), or else the first address
+    pub fn source_addr_for(&self, remote: Ipv4Addr) -> Option<Ipv4Addr> {
+        self.local_addrs
+            .iter()
+            .filter(|(addr, prefix_len)| same_subnet(*addr, remote, *prefix_len))
+            .max_by_key(|(_, prefix_len)| *prefix_len)
+            .or_else(|| self.local_addrs.first())
+            .map(|(addr, _)| *addr)
+    }
+}
+
+fn same_subnet(a: Ipv4Addr, b: Ipv4Addr, prefix_len: u8) -> bool {
+    let mask = u32::MAX
+        .checked_shl(32 - cmp::min(prefix_len, 32) as u32)
+        .unwrap_or(0);
+    u32::from(a) & mask == u32::from(b) & mask
+}
+
+// Low- and high-water marks of a connection's send or receive buffer, in bytes, see
+// TcpConfig::send_buffer and TcpConfig::recv_buffer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BufferMarks {
+    pub low: usize,
+    pub high: usize,
+}
+
+// How the IP Identification field of a connection's packets is filled in. Whatever the
+// strategy, packets that don't have DF set (and so may be fragmented) get IDs that don't repeat
+// any time soon; the strategy is about what the IDs give away to whoever sees them
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IpIdStrategy {
+    // One more for every packet, from a different starting point on every connection
+    #[default]
+    Incrementing,
+    // Unpredictable IDs, so an observer can't count packets or link flows together by them
+    Random,
+    // Zero on every packet with DF set, which all of a connection's packets are, as RFC 6864
+    // allows. Say as little as possible
+    Zero,
+}
+
+// How a connection in TIME-WAIT reacts to an in-window RST.
+//
+// RFC 793 has the RST close the connection right away. That lets an old duplicate RST (or an
+// attacker) cut TIME-WAIT short, after which old duplicate segments of the connection can be
+// taken for part of a new incarnation of it: TIME-WAIT assassination, see RFC 1337.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TimeWaitRst {
+    // Drop the RST and stay in TIME-WAIT for the full 2*MSL, as RFC 1337 recommends
+    #[default]
+    Ignore,
+    // Close the connection, as in RFC 793
+    Close,
+}
+
+// How a connection in TIME-WAIT reacts to a SYN (without ACK) from its peer.
+//
+// The peer may be opening the connection anew, which RFC 1122 Sec. 4.2.2.13 allows as long as
+// the SYN's sequence number is beyond everything the old connection used: then none of the old
+// connection's segments can be mistaken for part of the new one. A SYN that isn't beyond it is
+// an old duplicate, and is handled like any other segment TIME-WAIT has no use for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TimeWaitSyn {
+    // A SYN beyond the old connection ends TIME-WAIT and opens a new incarnation in its place,
+    // provided the application is done with the old one. Any other is acknowledged as an old
+    // duplicate
+    #[default]
+    Reincarnate,
+    // Every SYN is taken for an old duplicate and acknowledged, as RFC 793 does with any segment
+    // in TIME-WAIT that isn't acceptable. The peer can't get through until TIME-WAIT is over
+    Ack,
+    // Every SYN is dropped without an answer, and the peer keeps retransmitting it until
+    // TIME-WAIT is over
+    Drop,
+}
+
+// Which byte the urgent pointer of a segment points at. The pointer is an offset from the
+// segment's sequence number, and where it lands marks the end of the urgent data, but the
+// RFCs disagree on which side of it: RFC 793 has it point at the byte following the urgent
+// data, and RFC 1122 Sec. 4.2.2.4 "corrects" that to the last urgent byte itself. BSD kept to
+// RFC 793, and so did nearly every stack after it, which RFC 6093 made official. Picking the
+// other one than the peer is an off-by-one: the last urgent byte ends up in the stream, or the
+// first byte after the urgent data out of band.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UrgentPointer {
+    // The pointer is one past the last urgent byte (RFC 793, BSD, RFC 6093)
+    #[default]
+    FollowingByte,
+    // The pointer is at the last urgent byte (RFC 1122)
+    LastByte,
+}
+
+// How a connection treats data arriving after TcpStream::shutdown(Shutdown::Read).
+//
+// TCP has no way of telling the peer we won't read anymore: a FIN only says we won't send. So
+// shutting down the read side is purely local, and the peer finds out, if at all, by what we do
+// with the data it keeps sending.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReadShutdown {
+    // Acknowledge the data and throw it away, like Linux does. The peer's writes keep
+    // succeeding, and its data goes nowhere
+    #[default]
+    Discard,
+    // Reset the connection as soon as any data arrives, so the peer learns nobody's listening
+    // rather than sending into the void
+    Reset,
+}
@@ -0,0 +1,216 @@
+use std::io;
+use std::time::Instant;
+
+// A congestion control algorithm: decides how much data may be in flight at once (the
+// congestion window, cwnd), on top of whatever the peer's receive window allows.
+// Sizes are in bytes; `mss` is the most payload a segment carries on the connection.
+pub trait CongestionControl: Send {
+    // The name the algorithm is registered under, see from_name
+    fn name(&self) -> &'static str;
+    // How many bytes may currently be in flight
+    fn window(&self) -> usize;
+    // `acked` bytes of new data were acknowledged
+    fn on_ack(&mut self, acked: usize, mss: usize);
+    // The network signalled congestion while data still gets through: an ECN echo, or a
+    // single segment lost and caught by duplicate ACKs rather than by the timer
+    fn on_congestion(&mut self, in_flight: usize, mss: usize);
+    // The retransmission timer went off, i.e. something was most likely lost
+    fn on_timeout(&mut self, in_flight: usize, mss: usize);
+    // The last timeout turned out to be spurious (the data was only delayed, see F-RTO in
+    // tcp.rs): go back to the window from before it
+    fn undo_timeout(&mut self);
+}
+
+// Makes a new instance of an algorithm for a connection with the given MSS
+type Constructor = fn(usize) -> Box<dyn CongestionControl>;
+
+// Every algorithm that can be picked by name
+const ALGORITHMS: &[(&str, Constructor)] = &[
+    ("none", |_| Box::new(NoCongestionControl)),
+    ("reno", |mss| Box::new(Reno::new(mss))),
+    ("cubic", |mss| Box::new(Cubic::new(mss))),
+];
+
+// The names from_name accepts
+pub fn algorithm_names() -> impl Iterator<Item = &'static str> {
+    ALGORITHMS.iter().map(|(name, _)| *name)
+}
+
+// Makes a new instance of the algorithm registered as `name`, for a connection with the
+// given MSS. Fails with InvalidInput for names that aren't registered
+pub fn from_name(name: &str, mss: usize) -> io::Result<Box<dyn CongestionControl>> {
+    ALGORITHMS
+        .iter()
+        .find(|(registered, _)| *registered == name)
+        .map(|(_, new)| new(mss))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown congestion control algorithm {:?} (known ones: {})",
+                    name,
+                    algorithm_names().collect::<Vec<_>>().join(", ")
+                ),
+            )
+        })
+}
+
+// Initial congestion window (RFC 5681 Sec. 3.1)
+fn initial_window(mss: usize) -> usize {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
+
+// Only the peer's receive window limits what's in flight
+pub struct NoCongestionControl;
+
+impl CongestionControl for NoCongestionControl {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+    fn window(&self) -> usize {
+        usize::MAX
+    }
+    fn on_ack(&mut self, _acked: usize, _mss: usize) {}
+    fn on_congestion(&mut self, _in_flight: usize, _mss: usize) {}
+    fn on_timeout(&mut self, _in_flight: usize, _mss: usize) {}
+    fn undo_timeout(&mut self) {}
+}
+
+// Slow start and congestion avoidance as in RFC 5681: the window doubles every round trip
+// up to the slow start threshold, then grows by about one segment per round trip, and is
+// halved (or, after a timeout, collapsed to a single segment) on congestion
+pub struct Reno {
+    cwnd: usize,
+    ssthresh: usize,
+    // cwnd and ssthresh from before the last timeout, for undo_timeout
+    before_timeout: Option<(usize, usize)>,
+}
+
+impl Reno {
+    pub fn new(mss: usize) -> Self {
+        Reno {
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            before_timeout: None,
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+    fn on_ack(&mut self, acked: usize, mss: usize) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += std::cmp::min(acked, mss);
+        } else {
+            self.cwnd += std::cmp::max(1, mss * mss / self.cwnd);
+        }
+    }
+    fn on_congestion(&mut self, in_flight: usize, mss: usize) {
+        self.ssthresh = std::cmp::max(in_flight / 2, 2 * mss);
+        self.cwnd = self.ssthresh;
+    }
+    fn on_timeout(&mut self, in_flight: usize, mss: usize) {
+        self.before_timeout = Some((self.cwnd, self.ssthresh));
+        self.ssthresh = std::cmp::max(in_flight / 2, 2 * mss);
+        self.cwnd = mss;
+    }
+    fn undo_timeout(&mut self) {
+        if let Some((cwnd, ssthresh)) = self.before_timeout.take() {
+            self.cwnd = cwnd;
+            self.ssthresh = ssthresh;
+        }
+    }
+}
+
+// CUBIC (RFC 8312), without the TCP-friendly region: after a reduction the window grows along
+// a cubic function of the time since, quickly at first, flattening out around the window the
+// congestion happened at, then probing beyond it
+pub struct Cubic {
+    cwnd: usize,
+    ssthresh: usize,
+    // The window at the last congestion event, in bytes
+    w_max: usize,
+    // When the current congestion avoidance epoch started, if it has
+    epoch_start: Option<Instant>,
+    // Everything above from before the last timeout, for undo_timeout
+    before_timeout: Option<Box<Cubic>>,
+}
+
+impl Cubic {
+    // Scaling constant of the cubic function, and the factor the window is reduced by
+    const C: f64 = 0.4;
+    const BETA: f64 = 0.7;
+
+    pub fn new(mss: usize) -> Self {
+        Cubic {
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            w_max: 0,
+            epoch_start: None,
+            before_timeout: None,
+        }
+    }
+
+    fn reduce(&mut self, mss: usize) {
+        self.w_max = self.cwnd;
+        self.ssthresh = std::cmp::max((self.cwnd as f64 * Self::BETA) as usize, 2 * mss);
+        self.epoch_start = None;
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+    fn on_ack(&mut self, acked: usize, mss: usize) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += std::cmp::min(acked, mss);
+            return;
+        }
+
+        // W_cubic(t) = C*(t-K)^3 + W_max, in segments, with K the time it takes to get back to
+        // W_max (RFC 8312 Sec. 4.1)
+        let epoch_start = *self.epoch_start.get_or_insert_with(Instant::now);
+        let w_max = self.w_max as f64 / mss as f64;
+        let cwnd = self.cwnd as f64 / mss as f64;
+        let k = (w_max * (1.0 - Self::BETA) / Self::C).cbrt();
+        let t = epoch_start.elapsed().as_secs_f64();
+        let target = Self::C * (t - k).powi(3) + w_max;
+        if target > cwnd {
+            // Close (target - cwnd) segments of the gap over the next cwnd segments' ACKs
+            let increase = (target - cwnd) / cwnd * acked as f64;
+            self.cwnd += std::cmp::max(1, increase as usize);
+        }
+    }
+    fn on_congestion(&mut self, _in_flight: usize, mss: usize) {
+        self.reduce(mss);
+        self.cwnd = self.ssthresh;
+    }
+    fn on_timeout(&mut self, _in_flight: usize, mss: usize) {
+        self.before_timeout = Some(Box::new(Cubic {
+            before_timeout: None,
+            ..*self
+        }));
+        self.reduce(mss);
+        self.cwnd = mss;
+    }
+    fn undo_timeout(&mut self) {
+        if let Some(before) = self.before_timeout.take() {
+            *self = *before;
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// A running CRC-32 (the IEEE one used by Ethernet, zlib and friends) over a byte stream.
+//
+// Every connection keeps one over the bytes it has delivered to the application, so a test can
+// check a whole transfer arrived intact by comparing a single number with the one the sender
+// computed over what it wrote, instead of holding on to all the data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc32 {
+    // The CRC register, kept inverted between updates
+    state: u32,
+}
+
+// The reflected IEEE polynomial
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+// CRC of every possible byte, so updates go a byte at a time rather than a bit at a time
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32::default()
+    }
+
+    // Adds `data` to the end of the stream digested so far
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = !self.state;
+        for &byte in data {
+            crc = (crc >> 8) ^ TABLE[((crc ^ byte as u32) & 0xff) as usize];
+        }
+        self.state = !crc;
+    }
+
+    // The CRC of everything digested so far
+    pub fn value(&self) -> u32 {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc_of(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.value()
+    }
+
+    #[test]
+    fn known_answers() {
+        // The check value every CRC-32/IEEE implementation is measured against
+        assert_eq!(crc_of(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc_of(b""), 0);
+        assert_eq!(Crc32::new().value(), 0);
+    }
+
+    #[test]
+    fn chunked_updates_equal_a_single_one() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let whole = crc_of(&data);
+        for chunk_size in [1, 2, 7, 64, 999, 1000] {
+            let mut crc = Crc32::new();
+            for chunk in data.chunks(chunk_size) {
+                crc.update(chunk);
+            }
+            assert_eq!(crc.value(), whole, "chunks of {}", chunk_size);
+        }
+
+        // Empty updates in between change nothing either
+        let mut crc = Crc32::new();
+        crc.update(&data[..500]);
+        crc.update(&[]);
+        crc.update(&data[500..]);
+        assert_eq!(crc.value(), whole);
+    }
+}
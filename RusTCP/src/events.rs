@@ -0,0 +1,78 @@
+use std::time::SystemTime;
+
+use crate::metrics::{CloseReason, ConnectionStats};
+use crate::Quad;
+
+// Something that happened to a connection, as seen by the observer set with
+// Interface::set_observer
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub at: SystemTime,
+    pub quad: Quad,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "json-events",
+    derive(serde::Serialize),
+    serde(tag = "event", rename_all = "snake_case")
+)]
+pub enum EventKind {
+    // A connection came in on a listener, or we started opening one
+    Opened,
+    // The connection moved from one RFC 793 state to another
+    StateChanged {
+        from: &'static str,
+        to: &'static str,
+    },
+    // The connection went to CLOSED
+    Closed {
+        reason: CloseReason,
+    },
+    // A periodic snapshot of the connection, see STATS_INTERVAL
+    Stats(ConnectionStats),
+}
+
+// Receives every Event. It's called from the packet loop with the interface locked, so it must
+// be quick and must not call back into the Interface or its streams
+pub type Observer = Box<dyn FnMut(&Event) + Send>;
+
+// An observer writing every event to `out` as one line of JSON, e.g.
+//
+//  {"ts":1700000000.25,"quad":{...},"event":"state_changed","from":"SynRcvd","to":"Estab"}
+//
+// Write errors are reported on stderr (once) rather than disturbing the connections.
+#[cfg(feature = "json-events")]
+pub fn json_observer<W: std::io::Write + Send + 'static>(mut out: W) -> Observer {
+    #[derive(serde::Serialize)]
+    struct Line<'a> {
+        ts: f64,
+        quad: &'a Quad,
+        #[serde(flatten)]
+        kind: &'a EventKind,
+    }
+
+    let mut failed = false;
+    Box::new(move |event: &Event| {
+        let ts = event
+            .at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0.0, |since_epoch| since_epoch.as_secs_f64());
+        let line = Line {
+            ts,
+            quad: &event.quad,
+            kind: &event.kind,
+        };
+        let result = serde_json::to_writer(&mut out, &line)
+            .map_err(std::io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+            .and_then(|()| out.flush());
+        if let Err(e) = result {
+            if !failed {
+                eprintln!("Failed to write connection events: {}", e);
+                failed = true;
+            }
+        }
+    })
+}
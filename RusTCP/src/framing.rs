@@ -0,0 +1,70 @@
+use std::io::{self, Read, Write};
+
+use crate::TcpStream;
+
+// Length-prefixed message framing on top of a TcpStream.
+//
+// TCP only gives us a stream of bytes, so message boundaries have to be put back in by the
+// application. Here each message goes out as a 4 byte big endian length followed by that many
+// bytes of payload:
+//
+//  +--------+--------+--------+--------+--------- ~ ---------+
+//  |          length (u32, BE)         |  payload (length)   |
+//  +--------+--------+--------+--------+--------- ~ ---------+
+pub struct MessageStream {
+    stream: TcpStream,
+}
+
+impl MessageStream {
+    pub fn new(stream: TcpStream) -> Self {
+        MessageStream { stream }
+    }
+
+    // Gives back the underlying stream
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    // Sends `msg` as a single message
+    pub fn send_msg(&mut self, msg: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(msg.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "message too long to frame")
+        })?;
+        self.stream.write_all(&len.to_be_bytes())?;
+        self.stream.write_all(msg)
+    }
+
+    // Blocks until a whole message has arrived and returns its payload. A message may arrive
+    // spread across any number of reads, so this keeps reading until it has all of it.
+    //
+    // Returns Ok(None) if the peer closed the connection cleanly between messages, and an
+    // UnexpectedEof error if it closed in the middle of one.
+    pub fn recv_msg(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut prefix = [0u8; 4];
+        let mut nread = 0;
+        while nread < prefix.len() {
+            match self.stream.read(&mut prefix[nread..])? {
+                0 if nread == 0 => return Ok(None),
+                0 => return Err(closed_mid_message()),
+                n => nread += n,
+            }
+        }
+        let len = u32::from_be_bytes(prefix) as u64;
+
+        // Grow the buffer as the data actually arrives rather than trusting the length prefix
+        // with a potentially huge up-front allocation
+        let mut msg = Vec::new();
+        (&mut self.stream).take(len).read_to_end(&mut msg)?;
+        if (msg.len() as u64) < len {
+            return Err(closed_mid_message());
+        }
+        Ok(Some(msg))
+    }
+}
+
+fn closed_mid_message() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed in the middle of a message",
+    )
+}
@@ -0,0 +1,238 @@
+// A set of sequence number ranges, for keeping track of which parts of a sequence space we
+// have when they may arrive out of order: the data held for reassembly past a hole, or the
+// blocks a SACK option reports.
+//
+// Ranges are half-open, [start, end), and compared modulo 2^32 like any sequence numbers
+// (RFC 793 Sec. 3.3), so a range may wrap around from 2^32 - 1 to 0. That only works as long
+// as everything in the set lies within 2^31 of everything else, which a TCP window always
+// does. The set keeps its ranges sorted and coalesced: no two overlap or even touch, since
+// [a, b) and [b, c) together are just [a, c).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SeqIntervalSet {
+    ranges: Vec<(u32, u32)>,
+}
+
+// a < b in sequence space
+fn before(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 1 << 31
+}
+
+impl SeqIntervalSet {
+    pub fn new() -> Self {
+        SeqIntervalSet::default()
+    }
+
+    // Adds [start, end), merging it with every range it overlaps or touches. An empty range
+    // adds nothing
+    pub fn insert(&mut self, start: u32, end: u32) {
+        if !before(start, end) {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        // The first range that ends at or after `start`: everything before it is clear of the
+        // new range, and it's the first that may merge with it
+        let first = self.ranges.partition_point(|&(_, e)| before(e, start));
+        let mut last = first;
+        while let Some(&(s, e)) = self.ranges.get(last) {
+            if before(end, s) {
+                break;
+            }
+            if before(s, start) {
+                start = s;
+            }
+            if before(end, e) {
+                end = e;
+            }
+            last += 1;
+        }
+        self.ranges.splice(first..last, [(start, end)]);
+    }
+
+    // How far from `seq` on everything is in the set: the end of the range `seq` falls in, or
+    // `seq` itself if it isn't in any
+    pub fn contiguous_from(&self, seq: u32) -> u32 {
+        self.ranges
+            .iter()
+            .find(|&&(s, e)| !before(seq, s) && before(seq, e))
+            .map_or(seq, |&(_, e)| e)
+    }
+
+    // Forgets everything before `seq`, e.g. once RCV.NXT has moved past it. A range `seq`
+    // falls in is cut short to start there
+    pub fn remove_before(&mut self, seq: u32) {
+        self.ranges.retain(|&(_, e)| before(seq, e));
+        if let Some(first) = self.ranges.first_mut() {
+            if before(first.0, seq) {
+                first.0 = seq;
+            }
+        }
+    }
+
+    // The holes in the set between `from` and `to`, in order: the ranges there that aren't in it
+    pub fn gaps(&self, from: u32, to: u32) -> Vec<(u32, u32)> {
+        let mut gaps = Vec::new();
+        let mut at = from;
+        for &(s, e) in &self.ranges {
+            if !before(s, to) {
+                break;
+            }
+            if before(at, s) {
+                gaps.push((at, s));
+            }
+            if before(at, e) {
+                at = e;
+            }
+        }
+        if before(at, to) {
+            gaps.push((at, to));
+        }
+        gaps
+    }
+
+    // The ranges in the set, in sequence order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.ranges.iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // How many sequence numbers the set covers in all
+    pub fn covered(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|&(s, e)| e.wrapping_sub(s) as usize)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(set: &SeqIntervalSet) -> Vec<(u32, u32)> {
+        set.iter().collect()
+    }
+
+    #[test]
+    fn insert_keeps_ranges_sorted_and_apart() {
+        let mut set = SeqIntervalSet::new();
+        set.insert(300, 400);
+        set.insert(100, 200);
+        set.insert(500, 600);
+        assert_eq!(ranges(&set), [(100, 200), (300, 400), (500, 600)]);
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_touching_ranges() {
+        let mut set = SeqIntervalSet::new();
+        set.insert(100, 200);
+        set.insert(300, 400);
+        // Touching on the left, overlapping on the right
+        set.insert(200, 350);
+        assert_eq!(ranges(&set), [(100, 400)]);
+        // Swallowing a range whole, and reaching past both ends of the set
+        set.insert(500, 600);
+        set.insert(50, 700);
+        assert_eq!(ranges(&set), [(50, 700)]);
+        // Already covered
+        set.insert(60, 70);
+        assert_eq!(ranges(&set), [(50, 700)]);
+    }
+
+    #[test]
+    fn insert_ignores_empty_ranges() {
+        let mut set = SeqIntervalSet::new();
+        set.insert(100, 100);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn insert_across_the_wrap() {
+        let mut set = SeqIntervalSet::new();
+        set.insert(0xFFFF_FFF0, 0x10);
+        assert_eq!(ranges(&set), [(0xFFFF_FFF0, 0x10)]);
+        assert_eq!(set.covered(), 0x20);
+
+        // Ranges on either side of 2^32 that meet right at it
+        let mut set = SeqIntervalSet::new();
+        set.insert(0, 0x10);
+        set.insert(0xFFFF_FF00, 0);
+        assert_eq!(ranges(&set), [(0xFFFF_FF00, 0x10)]);
+
+        // Ordered by sequence, not numerically: the range past the wrap comes last
+        let mut set = SeqIntervalSet::new();
+        set.insert(0x20, 0x30);
+        set.insert(0xFFFF_FFE0, 0xFFFF_FFF0);
+        assert_eq!(ranges(&set), [(0xFFFF_FFE0, 0xFFFF_FFF0), (0x20, 0x30)]);
+        set.insert(0xFFFF_FFF0, 0x20);
+        assert_eq!(ranges(&set), [(0xFFFF_FFE0, 0x30)]);
+    }
+
+    #[test]
+    fn contiguous_from() {
+        let mut set = SeqIntervalSet::new();
+        set.insert(100, 200);
+        set.insert(300, 400);
+        assert_eq!(set.contiguous_from(100), 200);
+        assert_eq!(set.contiguous_from(150), 200);
+        // The end of a range isn't in it, and neither is a hole
+        assert_eq!(set.contiguous_from(200), 200);
+        assert_eq!(set.contiguous_from(250), 250);
+        assert_eq!(set.contiguous_from(300), 400);
+
+        let mut set = SeqIntervalSet::new();
+        set.insert(0xFFFF_FFF0, 0x10);
+        assert_eq!(set.contiguous_from(0xFFFF_FFF8), 0x10);
+        assert_eq!(set.contiguous_from(0), 0x10);
+    }
+
+    #[test]
+    fn remove_before() {
+        let mut set = SeqIntervalSet::new();
+        set.insert(100, 200);
+        set.insert(300, 400);
+        set.remove_before(150);
+        assert_eq!(ranges(&set), [(150, 200), (300, 400)]);
+        set.remove_before(200);
+        assert_eq!(ranges(&set), [(300, 400)]);
+        set.remove_before(400);
+        assert!(set.is_empty());
+
+        let mut set = SeqIntervalSet::new();
+        set.insert(0xFFFF_FFF0, 0x10);
+        set.insert(0x20, 0x30);
+        set.remove_before(0x8);
+        assert_eq!(ranges(&set), [(0x8, 0x10), (0x20, 0x30)]);
+    }
+
+    #[test]
+    fn gaps() {
+        let mut set = SeqIntervalSet::new();
+        assert_eq!(set.gaps(100, 500), [(100, 500)]);
+        set.insert(200, 300);
+        set.insert(400, 450);
+        assert_eq!(set.gaps(100, 500), [(100, 200), (300, 400), (450, 500)]);
+        // Bounds inside ranges
+        assert_eq!(set.gaps(250, 420), [(300, 400)]);
+        assert_eq!(set.gaps(200, 300), []);
+
+        let mut set = SeqIntervalSet::new();
+        set.insert(0xFFFF_FFF8, 0x8);
+        assert_eq!(
+            set.gaps(0xFFFF_FFF0, 0x10),
+            [(0xFFFF_FFF0, 0xFFFF_FFF8), (0x8, 0x10)]
+        );
+    }
+
+    #[test]
+    fn covered() {
+        let mut set = SeqIntervalSet::new();
+        assert_eq!(set.covered(), 0);
+        set.insert(100, 200);
+        set.insert(150, 250);
+        set.insert(300, 310);
+        assert_eq!(set.covered(), 160);
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use crate::config::IpIdStrategy;
+
+// Hands out the IP Identification of every packet a connection sends, as TcpConfig::ip_id
+// says. The ID only matters for putting fragments back together (RFC 791): all fragments of a
+// packet carry its ID, so packets that might be fragmented need IDs that don't repeat while
+// they're in the network (RFC 6864 Sec. 4). Packets with DF set are never fragmented, so
+// their ID means nothing, and may even be zero.
+pub(crate) struct IpIds {
+    strategy: IpIdStrategy,
+    // The next ID for the incrementing strategy
+    next: u16,
+    // State of the xorshift generator behind the random strategy. Never zero
+    rng: u64,
+}
+
+impl IpIds {
+    pub(crate) fn new(strategy: IpIdStrategy) -> Self {
+        // RandomState is seeded from the OS for every new instance, which saves depending on a
+        // random number crate for a few unpredictable bits
+        let seed = RandomState::new().build_hasher().finish() | 1;
+        IpIds {
+            strategy,
+            // Even the incrementing IDs start somewhere different on every connection, so they
+            // don't give away how many packets we've sent to others
+            next: seed as u16,
+            rng: seed,
+        }
+    }
+
+    // The ID for the next packet, which has DF set or not as `dont_fragment` says
+    pub(crate) fn next(&mut self, dont_fragment: bool) -> u16 {
+        match self.strategy {
+            IpIdStrategy::Zero if dont_fragment => 0,
+            IpIdStrategy::Random => {
+                // xorshift64 (Marsaglia): plenty for IDs meant to be unguessable by an
+                // observer rather than by someone attacking the generator
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                (self.rng >> 32) as u16
+            }
+            // A packet that may be fragmented needs a unique ID even when the strategy is
+            // zero IDs
+            IpIdStrategy::Incrementing | IpIdStrategy::Zero => {
+                let id = self.next;
+                self.next = self.next.wrapping_add(1);
+                id
+            }
+        }
+    }
+}
+
+// IDs for the packets we send outside of any connection (RSTs in reply to stray segments, and
+// raw segments). There's no connection configuration to go by for them, so they just count up,
+// on one counter shared by all of them
+pub(crate) fn next_stateless() -> u16 {
+    static NEXT: AtomicU16 = AtomicU16::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
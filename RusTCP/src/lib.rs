@@ -0,0 +1,1740 @@
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+mod buffer_pool;
+pub mod compat;
+mod config;
+pub mod congestion;
+#[cfg(feature = "delivered-digest")]
+mod digest;
+mod events;
+mod framing;
+mod interval;
+mod ip_id;
+mod metrics;
+mod nic;
+mod pool;
+#[cfg(feature = "raw-segment")]
+mod raw;
+#[cfg(feature = "seq-trace")]
+mod seq_trace;
+mod stream_buffers;
+mod tcp;
+mod trace;
+
+pub use config::{
+    BufferMarks, IpIdStrategy, ReadShutdown, TcpConfig, TimeWaitRst, TimeWaitSyn, UrgentPointer,
+    READ_BUFFER_SIZES,
+};
+#[cfg(feature = "delivered-digest")]
+pub use digest::Crc32;
+#[cfg(feature = "json-events")]
+pub use events::json_observer;
+pub use events::{Event, EventKind, Observer};
+pub use framing::MessageStream;
+pub use interval::SeqIntervalSet;
+pub use metrics::{CloseReason, ConnectionStats, ListenerMetrics, WindowInfo};
+use nic::Nic;
+pub use pool::ConnectionPool;
+#[cfg(feature = "raw-segment")]
+pub use raw::SegmentSpec;
+use stream_buffers::{RecvBuffer, SendBuffer, Shared};
+pub use tcp::State;
+
+// How often the packet loop runs every connection's timers
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+// Most frames the packet loop reads in one go before sending the ACKs they call for
+const RECV_BATCH: usize = 64;
+
+// How often the observer gets a Stats event for every connection
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Receive buffer of the discard service's connections, and how much each of its reads takes:
+// as much as the window can ever be without window scaling, so one read empties it
+const DISCARD_BUFFER: usize = 64 * 1024;
+
+// How much send_from_reader reads from its source at a time
+const SEND_CHUNK: usize = 16 * 1024;
+
+// Local ports handed out to the connections we open (the IANA dynamic port range)
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+// Connection Quad: Unique Identifier for TCP connections
+// Used as a key in TCB (Transmission Control Block) Hashmap
+// 4-tuple of source IP, source port, destination IP, and destination port
+// (source is the remote peer, destination is us, as seen on incoming packets)
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+#[cfg_attr(feature = "json-events", derive(serde::Serialize))]
+pub struct Quad {
+    pub source_socket: (Ipv4Addr, u16),
+    pub destination_socket: (Ipv4Addr, u16),
+}
+
+// Everything the packet loop and the user-facing handles (Listener, TcpStream) share.
+// It all lives behind a single lock; the condition variables let user threads sleep until the
+// packet loop has something for them.
+#[derive(Default)]
+struct InterfaceState {
+    manager: Mutex<ConnectionManager>,
+    // Signalled when a new connection is waiting to be accepted
+    pending_var: Condvar,
+    // Signalled when connections may have new data to read (or have seen EOF)
+    rcv_var: Condvar,
+    // Signalled when connections may have freed up space in their send queue
+    snd_var: Condvar,
+}
+
+type InterfaceHandle = Arc<InterfaceState>;
+
+#[derive(Default)]
+struct ConnectionManager {
+    // Tells the packet loop to shut down
+    terminate: bool,
+    // The packet loop has failed and stopped, see run_packet_loop. Nothing works anymore
+    down: bool,
+    // No new connections are taken, see Interface::shutdown
+    shutting_down: bool,
+    // Settings every new connection starts out with
+    config: TcpConfig,
+    // Initialize a HashMap to store TCP connection states against their connection Quad tuple
+    connections: HashMap<Quad, tcp::Connection>,
+    // Everything we keep for each bound port
+    listeners: HashMap<u16, ListenerState>,
+    // Connection counters, per listening port
+    metrics: metrics::Metrics,
+    // Gets told about everything that happens to connections, see Interface::set_observer
+    observer: Option<Observer>,
+    // Where to start looking for a free ephemeral port for the next connect
+    next_ephemeral_port: u16,
+    // Connections Interface::connect wants opened, waiting for the packet loop to send their SYN
+    pending_connects: VecDeque<Quad>,
+    // Hand-crafted segments waiting for the packet loop to send them
+    #[cfg(feature = "raw-segment")]
+    raw_segments: VecDeque<(Quad, SegmentSpec)>,
+    // What to do with IPv4 packets of protocols other than TCP, by protocol number
+    protocol_handlers: HashMap<u8, ProtocolHandler>,
+    // Most buffer memory all connections together may take up, see Interface::set_memory_budget
+    memory_budget: Option<usize>,
+    // How long everything we send is held back, see Interface::set_artificial_delay
+    artificial_delay: Duration,
+}
+
+impl ConnectionManager {
+    // How much more buffer memory the connections may take on before they're over the budget,
+    // if there is one
+    fn memory_room(&self) -> Option<usize> {
+        let budget = self.memory_budget?;
+        let committed: usize = self
+            .connections
+            .values()
+            .map(|c| c.memory_committed())
+            .sum();
+        Some(budget.saturating_sub(committed))
+    }
+}
+
+// Handles the IPv4 packets of one IP protocol, see Interface::register_protocol_handler. It
+// gets each whole packet (IP header included), and returns the IP packets to send in reply,
+// if any
+pub type ProtocolHandler = Box<dyn FnMut(&[u8]) -> Vec<Vec<u8>> + Send>;
+
+// IP protocol number of TCP, which the stack handles itself
+const IPPROTO_TCP: u8 = 6;
+
+#[derive(Default)]
+struct ListenerState {
+    // Established connections not yet handed out by Listener::accept. A connection only joins
+    // once its handshake is done; until then it's in the connection map alone
+    pending: VecDeque<Quad>,
+    // Decides which connection attempts may go ahead, see Listener::set_accept_filter
+    accept_filter: Option<AcceptFilter>,
+    // What to do with the connection attempts the filter turns down
+    reject_action: RejectAction,
+}
+
+// A predicate deciding, from its quad, whether an incoming connection may be accepted
+pub type AcceptFilter = Box<dyn Fn(&Quad) -> bool + Send>;
+
+// How we answer a connection attempt that's been turned down by an accept filter
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RejectAction {
+    // Reply with a RST, so the peer immediately sees "connection refused"
+    #[default]
+    Reset,
+    // Ignore the SYN entirely, so the peer retries until it gives up (as if nothing were there)
+    Drop,
+}
+
+// A TUN-backed network interface running our TCP implementation.
+// Packets are processed on a background thread; the user interacts with it through the
+// Listener and TcpStream handles it gives out.
+pub struct Interface {
+    ih: Option<InterfaceHandle>,
+    jh: Option<thread::JoinHandle<io::Result<()>>>,
+    // Only for an offline interface, see Interface::offline. Behind a mutex of its own so it can
+    // be driven from one thread while others block in calls like connect
+    offline: Option<Mutex<Offline>>,
+}
+
+// What an offline interface has in place of the packet loop: a NIC with no device, which keeps
+// everything the stack sends, and the bookkeeping the loop would otherwise do
+struct Offline {
+    nic: Nic,
+    last_stats: Instant,
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        // Listeners and streams can outlive the interface, but with no one left to move their
+        // connections along, anyone blocked on one would wait forever: they find it down instead
+        let ih = self.ih.take().unwrap();
+        ih.manager.lock().unwrap().terminate = true;
+        take_down(&ih);
+        drop(ih);
+        // Unless `wait` already did. A failed loop has logged its error when it stopped
+        if let Some(jh) = self.jh.take() {
+            let _ = jh.join().unwrap();
+        }
+    }
+}
+
+impl Interface {
+    // Creates the "tun0" TUN device and starts processing packets on it, with the default
+    // configuration
+    pub fn new() -> io::Result<Self> {
+        Self::with_config(TcpConfig::default())
+    }
+
+    // Like `new`, with the given settings for all connections
+    pub fn with_config(config: TcpConfig) -> io::Result<Self> {
+        check_config(&config)?;
+
+        // Create a new virtual NIC named "tun0" in TUN mode.
+        let nic = Nic::open("tun0", config.segmentation_offload)?;
+
+        let ih: InterfaceHandle = Arc::default();
+        ih.manager.lock().unwrap().config = config;
+
+        let jh = {
+            let ih = ih.clone();
+            thread::spawn(move || run_packet_loop(nic, ih))
+        };
+
+        Ok(Interface {
+            ih: Some(ih),
+            jh: Some(jh),
+            offline: None,
+        })
+    }
+
+    // An interface with no TUN device and no packet loop behind it: nothing comes in unless
+    // it's handed over with handle_frame, and what the stack sends is kept for
+    // take_sent_frames. Nor does anything happen on its own: the caller runs each tick with
+    // `tick`, which is when timers fire and what the application writes goes out. Good for
+    // feeding the stack crafted frames (in tests, say) and checking its answers
+    pub fn offline(config: TcpConfig) -> io::Result<Self> {
+        check_config(&config)?;
+        let ih: InterfaceHandle = Arc::default();
+        ih.manager.lock().unwrap().config = config;
+        Ok(Interface {
+            ih: Some(ih),
+            jh: None,
+            offline: Some(Mutex::new(Offline {
+                nic: Nic::capture(),
+                last_stats: Instant::now(),
+            })),
+        })
+    }
+
+    // Runs one tick of an offline interface, as the packet loop does every TICK_INTERVAL:
+    // pending connects send their SYN, every connection's timers are checked and what its
+    // application has written goes out (as far as the windows allow). Timers go by the clock,
+    // so one that's not due yet won't fire however many ticks are run
+    pub fn tick(&self) -> io::Result<()> {
+        let ih = self.ih.as_ref().unwrap();
+        let mut offline = self
+            .offline
+            .as_ref()
+            .ok_or_else(not_offline)?
+            .lock()
+            .unwrap();
+        let offline = &mut *offline;
+        run_tick(&mut offline.nic, ih, &mut offline.last_stats)?;
+        offline.nic.flush_delayed()
+    }
+
+    // The frames an offline interface has sent since the last call, oldest first, each starting
+    // with the 4 byte flags + protocol prefix as it would have gone to the TUN device
+    pub fn take_sent_frames(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut offline = self
+            .offline
+            .as_ref()
+            .ok_or_else(not_offline)?
+            .lock()
+            .unwrap();
+        Ok(offline.nic.take_captured())
+    }
+
+    // Starts accepting connections on the given port
+    pub fn bind(&self, port: u16) -> io::Result<Listener> {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        match cm.listeners.entry(port) {
+            Entry::Vacant(v) => {
+                v.insert(ListenerState::default());
+            }
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "port already bound",
+                ));
+            }
+        };
+        drop(cm);
+        Ok(Listener {
+            port,
+            h: self.ih.as_ref().unwrap().clone(),
+        })
+    }
+
+    // Opens a connection to `remote` and blocks until it's established. The source address is
+    // picked from the configured local addresses by which subnet `remote` is on (see
+    // TcpConfig::source_addr_for). Fails with ConnectionRefused if the peer turns it down, and
+    // TimedOut if it never answers.
+    pub fn connect(&self, remote: (Ipv4Addr, u16)) -> io::Result<TcpStream> {
+        let local_addr = {
+            let cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+            cm.config.source_addr_for(remote.0)
+        };
+        let local_addr = local_addr.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no local address configured",
+            )
+        })?;
+        self.connect_from(local_addr, remote)
+    }
+
+    // Like `connect`, but from the given local address, which must be one of the configured ones
+    pub fn connect_from(
+        &self,
+        local_addr: Ipv4Addr,
+        remote: (Ipv4Addr, u16),
+    ) -> io::Result<TcpStream> {
+        let h = self.ih.as_ref().unwrap();
+        let mut cm = h.manager.lock().unwrap();
+        if cm.down {
+            return Err(interface_down());
+        }
+        if cm.shutting_down {
+            return Err(shutting_down());
+        }
+        if !cm
+            .config
+            .local_addrs
+            .iter()
+            .any(|(addr, _)| *addr == local_addr)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} is not one of our addresses", local_addr),
+            ));
+        }
+
+        // Find a local port that's neither listened on nor in use towards this peer
+        let ports = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+        let quad = (0..ports)
+            .map(|i| {
+                let offset = cm.next_ephemeral_port.wrapping_add(i) % ports;
+                Quad {
+                    source_socket: remote,
+                    destination_socket: (local_addr, EPHEMERAL_PORTS.start() + offset),
+                }
+            })
+            .find(|quad| {
+                !cm.listeners.contains_key(&quad.destination_socket.1)
+                    && !cm.connections.contains_key(quad)
+                    && !cm.pending_connects.contains(quad)
+            })
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "no free ephemeral port")
+            })?;
+        cm.next_ephemeral_port = quad.destination_socket.1 - EPHEMERAL_PORTS.start() + 1;
+
+        // The packet loop owns the NIC, so it's the one that sends our SYN
+        cm.pending_connects.push_back(quad);
+        loop {
+            match cm.connections.get(&quad) {
+                Some(connection) if connection.is_closed() => {
+                    let error = match connection.close_reason() {
+                        Some(CloseReason::Timeout) => timed_out(),
+                        _ => io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"),
+                    };
+                    cm.connections.remove(&quad);
+                    return Err(error);
+                }
+                Some(connection) if !connection.is_connecting() => {
+                    return Ok(TcpStream::new(quad, h.clone(), connection));
+                }
+                _ => {}
+            }
+            if cm.down {
+                return Err(interface_down());
+            }
+            cm = h.rcv_var.wait(cm).unwrap();
+        }
+    }
+
+    // Sends an arbitrary TCP segment from our side of `quad`, bypassing the state machine
+    // entirely: the flags, sequence/ack numbers, window and payload are exactly as given in
+    // `spec`. Only the IP framing and checksums are filled in for it.
+    //
+    // This is an escape hatch for testing how peers react to unusual segments (out-of-window
+    // data, bogus ACKs, ...) and for reproducing bugs, which is why it's behind the
+    // "raw-segment" feature. The segment goes out on the packet loop's next tick.
+    #[cfg(feature = "raw-segment")]
+    pub fn send_raw_segment(&self, quad: Quad, spec: SegmentSpec) -> io::Result<()> {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.raw_segments.push_back((quad, spec));
+        Ok(())
+    }
+
+    // The settings the interface's connections run with
+    pub fn config(&self) -> TcpConfig {
+        let cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.config.clone()
+    }
+
+    // Has `observer` called for every connection event from now on: connections opening,
+    // changing state and closing, plus periodic stats. Replaces any previous observer.
+    // With the "json-events" feature, json_observer makes one that writes JSON lines.
+    pub fn set_observer(&self, observer: Observer) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.observer = Some(observer);
+    }
+
+    // Starts shutting the interface down: from now on connection attempts from peers are
+    // refused with a RST, and `connect` fails, while the connections already open carry on
+    // until they close. Dropping the Interface is what finally stops the packet loop
+    pub fn shutdown(&self) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.shutting_down = true;
+    }
+
+    // Caps the memory the send and receive buffers of all connections together may take up, in
+    // bytes. Connections near the cap are slowed down rather than let it grow: receive windows
+    // stop opening up (and new connections start out with small ones), and writes block until
+    // there's room again. Applies from now on; memory already over the budget isn't reclaimed
+    // other than by the applications reading and the peers acknowledging
+    pub fn set_memory_budget(&self, bytes: usize) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.memory_budget = Some(bytes);
+        // Whether there's room for a write is the interface's to say from now on
+        for connection in cm.connections.values_mut() {
+            connection.close_writes();
+        }
+    }
+
+    // Holds back every segment we send for `delay` before it goes out on the TUN device, for
+    // seeing how the stack behaves over a long fat link without needing one: with the peer on
+    // the same host, the round trip becomes `delay` plus next to nothing. It can be changed at
+    // any time and takes effect within a tick; Duration::ZERO turns it off again.
+    //
+    // Two things to keep in mind when doing so. The retransmission timeout isn't estimated
+    // from round trip samples here, it starts over at one second with every ACK that moves
+    // forward, so a delay close to or above a second gets segments retransmitted that were
+    // never lost. And we don't do window scaling, so no more than 64KiB can be in flight
+    // either way: one window per round trip is as fast as a transfer gets (128KiB/s at 500ms).
+    pub fn set_artificial_delay(&self, delay: Duration) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.artificial_delay = delay;
+    }
+
+    // Whether the interface has stopped working, see `wait`
+    pub fn is_down(&self) -> bool {
+        self.ih.as_ref().unwrap().manager.lock().unwrap().down
+    }
+
+    // Blocks until the packet loop stops, which it only does by itself when it fails, and
+    // returns what it failed with. If the TUN device went away (deleted with `ip link del`,
+    // say), that's a NetworkDown error, and a supervisor can set up a new Interface once the
+    // device is back. By then every connection of this one has failed with NetworkDown too.
+    // An offline interface has no loop to wait for, and returns right away
+    pub fn wait(mut self) -> io::Result<()> {
+        match self.jh.take() {
+            Some(jh) => jh.join().unwrap(),
+            None => Ok(()),
+        }
+    }
+
+    // Hands every IPv4 packet of protocol `proto` to `handler` from now on, replacing any
+    // handler registered for it before. This is how protocols other than TCP (UDP, ICMP, ...)
+    // can be served on the interface; packets of protocols without a handler are dropped.
+    // TCP itself can't be taken over, that's what the rest of the stack is for.
+    //
+    // The handler runs on the packet loop, so it should be quick about it.
+    pub fn register_protocol_handler(&self, proto: u8, handler: ProtocolHandler) -> io::Result<()> {
+        if proto == IPPROTO_TCP {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TCP is handled by the stack itself",
+            ));
+        }
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.protocol_handlers.insert(proto, handler);
+        Ok(())
+    }
+
+    // A snapshot of the connection counters of every port that's been listened on
+    pub fn metrics(&self) -> HashMap<u16, ListenerMetrics> {
+        let cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.metrics.listeners.clone()
+    }
+
+    // Formats the TCB of every connection, see tcp::Connection::debug_dump
+    pub fn debug_dump(&self) -> String {
+        let cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        let mut out = String::new();
+        for (quad, connection) in cm.connections.iter() {
+            out.push_str(&format!("{:?}\n{}\n\n", quad, connection.debug_dump()));
+        }
+        out
+    }
+}
+
+// Turns down settings no connection could run with, before any interface uses them
+fn check_config(config: &TcpConfig) -> io::Result<()> {
+    // An unknown algorithm name would otherwise only show up on the first connection
+    congestion::from_name(&config.congestion_control, tcp::DEFAULT_MSS)?;
+    if !READ_BUFFER_SIZES.contains(&config.read_buffer_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "read buffer size {} is outside {}..={}",
+                config.read_buffer_size,
+                READ_BUFFER_SIZES.start(),
+                READ_BUFFER_SIZES.end()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// What driving an interface by hand fails with when it has a packet loop doing that already:
+// what the two of them sent would end up in either place, and frames would race each other
+fn not_offline() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only an offline interface can be driven by hand, this one has a packet loop",
+    )
+}
+
+// Runs the packet loop, and takes the interface down if it fails: without a working NIC no
+// connection can go on, so they all fail from then on, and anyone blocked on one is woken up
+// to find out
+fn run_packet_loop(nic: Nic, ih: InterfaceHandle) -> io::Result<()> {
+    let result = packet_loop(nic, ih.clone());
+    if let Err(e) = &result {
+        eprintln!("Packet loop failed, taking the interface down: {}", e);
+        take_down(&ih);
+    }
+    result.map_err(|e| {
+        if device_gone(&e) {
+            io::Error::new(
+                io::ErrorKind::NetworkDown,
+                format!("TUN device is gone ({})", e),
+            )
+        } else {
+            e
+        }
+    })
+}
+
+// Marks the interface down and wakes up everyone blocked on it, so they fail with
+// interface_down from now on
+fn take_down(ih: &InterfaceState) {
+    let mut cm = ih.manager.lock().unwrap();
+    cm.down = true;
+    // Writes can't be let through on their own anymore, they have to fail
+    for connection in cm.connections.values_mut() {
+        connection.close_writes();
+    }
+    drop(cm);
+    ih.pending_var.notify_all();
+    ih.rcv_var.notify_all();
+    ih.snd_var.notify_all();
+}
+
+// Whether a NIC error means the TUN device itself has disappeared from under us, rather than
+// a single read or write going wrong
+fn device_gone(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENODEV | libc::ENXIO | libc::EIO | libc::EBADFD)
+    )
+}
+
+// Receives frames from the NIC and dispatches them to their connections, running every
+// connection's timers in between. Runs on the Interface's background thread.
+fn packet_loop(mut nic: Nic, ih: InterfaceHandle) -> io::Result<()> {
+    // The buffer every frame is read into, sized by TcpConfig::read_buffer_size (1504 bytes by
+    // default: a 1500 byte packet, the standard MTU, plus the frame prefix)
+    let read_buffer_size = ih.manager.lock().unwrap().config.read_buffer_size;
+    let mut buf = vec![0u8; read_buffer_size];
+
+    let mut last_tick = Instant::now();
+    let mut last_stats = Instant::now();
+
+    // Main loop to continuously receive data from the interface.
+    loop {
+        // Every so often, give each connection a chance to act on its own (e.g. send what the
+        // application has queued up)
+        if last_tick.elapsed() >= TICK_INTERVAL {
+            if !run_tick(&mut nic, &ih, &mut last_stats)? {
+                return Ok(());
+            }
+            last_tick = Instant::now();
+        }
+
+        // Frames held back by an artificial delay go out once it's up. This runs at least every
+        // tick, so they're late by at most that much on top of the delay
+        nic.flush_delayed()?;
+
+        // Wait (at most one tick) for the NIC to have a frame for us
+        if !frame_ready(&nic, TICK_INTERVAL)? {
+            continue;
+        }
+
+        // Handle every frame that's already waiting (up to a limit), then acknowledge them all
+        // at once: a burst of segments for a connection gets one ACK instead of one each
+        for _ in 0..RECV_BATCH {
+            // Receive data from the TUN interface and store the number of bytes received in `nbytes`.
+            let nbytes = nic.recv(&mut buf[..])?;
+            route_frame(&mut nic, &ih, &buf[..nbytes])?;
+            if !frame_ready(&nic, Duration::ZERO)? {
+                break;
+            }
+        }
+        flush_acks(&mut nic, &ih)?;
+    }
+}
+
+// Gives each connection a chance to act on its own (e.g. send what the application has queued
+// up), as the packet loop does every tick. Returns false, having done nothing, if the interface
+// is to shut down
+fn run_tick(nic: &mut Nic, ih: &InterfaceState, last_stats: &mut Instant) -> io::Result<bool> {
+    let mut cm_guard = ih.manager.lock().unwrap();
+    let cm = &mut *cm_guard;
+    if cm.terminate {
+        return Ok(false);
+    }
+    nic.set_delay(cm.artificial_delay);
+    while let Some(quad) = cm.pending_connects.pop_front() {
+        let connection = tcp::Connection::connect(
+            nic,
+            quad.destination_socket,
+            quad.source_socket,
+            &cm.config,
+            cm.memory_room(),
+        )?;
+        cm.connections.insert(quad, connection);
+        emit(&mut cm.observer, quad, EventKind::Opened);
+    }
+    let mut memory_room = cm.memory_room();
+    // Highest priority first (see tcp::Connection::set_priority)
+    let mut by_priority: Vec<Quad> = cm.connections.keys().copied().collect();
+    by_priority.sort_by_key(|quad| cmp::Reverse(cm.connections[quad].priority()));
+    for quad in by_priority {
+        let connection = cm.connections.get_mut(&quad).unwrap();
+        let state_before = connection.state_name();
+        let committed_before = connection.memory_committed();
+        #[cfg(feature = "seq-trace")]
+        let seq_before = connection.seq_spaces();
+        connection.on_tick(nic, memory_room)?;
+        #[cfg(feature = "seq-trace")]
+        seq_trace::log_change(&quad, "tick", seq_before, connection.seq_spaces());
+        connection.check_invariants();
+        // What the connection took on (or let go of) changes what's left for the rest
+        memory_room = memory_room
+            .map(|room| (room + committed_before).saturating_sub(connection.memory_committed()));
+        record_changes(
+            &mut cm.metrics,
+            &mut cm.observer,
+            quad,
+            connection,
+            state_before,
+        );
+    }
+    if cm.observer.is_some() && last_stats.elapsed() >= STATS_INTERVAL {
+        for (quad, connection) in cm.connections.iter() {
+            emit(
+                &mut cm.observer,
+                *quad,
+                EventKind::Stats(connection.stats()),
+            );
+        }
+        *last_stats = Instant::now();
+    }
+    // Closed connections no one holds a stream for anymore are done with
+    cm.connections
+        .retain(|_, connection| !connection.is_finished());
+    #[cfg(feature = "raw-segment")]
+    while let Some((quad, spec)) = cm.raw_segments.pop_front() {
+        raw::send(nic, &quad, &spec)?;
+    }
+
+    // A tick may have changed things for blocked readers and writers too (e.g. by aborting
+    // their connection)
+    drop(cm_guard);
+    ih.rcv_var.notify_all();
+    ih.snd_var.notify_all();
+    Ok(true)
+}
+
+// Sends the ACKs the frames handled since the last call held back, one per connection: a burst
+// of segments for a connection gets one ACK instead of one each
+fn flush_acks(nic: &mut Nic, ih: &InterfaceState) -> io::Result<()> {
+    let mut cm = ih.manager.lock().unwrap();
+    for connection in cm.connections.values_mut() {
+        connection.flush_ack(nic)?;
+    }
+    Ok(())
+}
+
+// Waits up to `timeout` for the NIC to have a frame for us to read. A signal interrupting the
+// wait counts as no frame
+fn frame_ready(nic: &Nic, timeout: Duration) -> io::Result<bool> {
+    let mut pfd = [libc::pollfd {
+        fd: nic.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    let ready = unsafe { libc::poll(pfd.as_mut_ptr(), 1, timeout.as_millis() as libc::c_int) };
+    if ready < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    Ok(ready > 0)
+}
+
+// Hands an offline interface (see Interface::offline) one frame as the TUN device would
+// (starting with its 4 byte flags + protocol prefix). What the stack sends in reply is kept for
+// Interface::take_sent_frames, ACKs the frame calls for included, as after a batch on the
+// packet loop: a data segment gets its ACK right away (unless it's one a delayed ACK would
+// wait out).
+//
+// Tests can play the peer this way, with crafted frames, without a TUN device or the privileges
+// it takes. An interface with a packet loop handles the frames of its device itself, so this
+// fails with Unsupported there.
+pub fn handle_frame(state: &Interface, frame: &[u8]) -> io::Result<()> {
+    let ih = state.ih.as_ref().unwrap();
+    let mut offline = state
+        .offline
+        .as_ref()
+        .ok_or_else(not_offline)?
+        .lock()
+        .unwrap();
+    route_frame(&mut offline.nic, ih, frame)?;
+    flush_acks(&mut offline.nic, ih)?;
+    offline.nic.flush_delayed()
+}
+
+// Handles one frame as read from the TUN device (starting with its 4 byte flags + protocol
+// prefix): parses the IP and TCP headers and hands the segment to its connection, or to the
+// listener on its port if it opens a new one. Anything that isn't TCP over IPv4 is ignored.
+fn route_frame(nic: &mut Nic, ih: &InterfaceState, frame: &[u8]) -> io::Result<()> {
+    // TUN/TAP frame format (source: https://www.kernel.org/doc/Documentation/networking/tuntap.txt sec. 3.2):
+    // [Note: big endian ordering]
+    // First 2 bytes: Flags
+    // Second 2 bytes: Protocol
+    // Remainder: Raw protocol frame
+    //
+    // A frame that ends with (or before the end of) that prefix has no packet in it to parse
+    if frame.len() <= 4 {
+        eprintln!("Skipping a {} byte frame with no packet in it", frame.len());
+        return Ok(());
+    }
+    let _flags = u16::from_be_bytes([frame[0], frame[1]]);
+    let proto = u16::from_be_bytes([frame[2], frame[3]]);
+
+    if proto != 0x0800 {
+        // Skip if protocol neq IPv4 (https://en.wikipedia.org/wiki/EtherType#Values)
+        return Ok(());
+    }
+
+    // Try to parse IPv4 header from raw protocol frame buffer slice:
+    //
+    // IPv4 Header Format
+    // ====================
+    // Offsets | Octet |  0  1  2  3  4  5  6  7  8  9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    // ---------|-------|------------------------------------------------------------------------------------------------
+    //    0     |   0   | Version |  IHL  |    DSCP   |ECN|                    Total Length                           |
+    //    4     |  32   |                Identification                |  Flags  |         Fragment Offset         |
+    //    8     |  64   |    Time To Live   |    Protocol    |                 Header Checksum                      |
+    //   12     |  96   |                                    Source IP Address                                       |
+    //   16     | 128   |                                 Destination IP Address                                    |
+    //   20     | 160   |                                                                                            |
+    //    :     |   :   |                              Options (if IHL > 5)                                       |
+    //   56     | 448   |                                                                                            |
+    //
+
+    match etherparse::Ipv4HeaderSlice::from_slice(&frame[4..]) {
+        // If parsing is successful, proceed with parsed packet
+        Ok(ipv4_header) => {
+            // The packet must all be there. A read only returns as much of a packet as fits the
+            // read buffer, so a packet too big for it (e.g. a jumbo frame, see
+            // TcpConfig::read_buffer_size) comes in cut short, and its tail would otherwise be
+            // taken for the whole payload. Anything past the total length is link padding
+            let packet_len = ipv4_header.total_len() as usize;
+            if frame.len() - 4 < packet_len {
+                eprintln!(
+                    "Dropping a {} byte packet, only {} bytes of which were read",
+                    packet_len,
+                    frame.len() - 4
+                );
+                return Ok(());
+            }
+            let frame = &frame[..4 + packet_len];
+            // extract source IP address from parsed packet
+            let source_addr = ipv4_header.source_addr();
+            // extract destination IP address
+            let destination_addr = ipv4_header.destination_addr();
+            // extract protocol number (TCP is typically 6 (0x06))
+            let protocol = ipv4_header.protocol();
+
+            if protocol != IPPROTO_TCP {
+                // Anything but TCP goes to whoever registered for it, if anyone did
+                let packet = &frame[4..];
+                let mut cm = ih.manager.lock().unwrap();
+                if let Some(handler) = cm.protocol_handlers.get_mut(&protocol) {
+                    for reply in handler(packet) {
+                        send_packet(nic, &reply)?;
+                    }
+                }
+                return Ok(());
+            }
+
+            // Try to parse TCP header from raw frame buffer slice
+            // Adjust starting slice based on length of IPv4 header. The header slice covers the
+            // full IHL * 4 bytes, so any IPv4 options (record route, timestamps, ...) are skipped
+            // along with the fixed 20 bytes. We ignore the options themselves; nothing in TCP
+            // depends on them.
+            match etherparse::TcpHeaderSlice::from_slice(&frame[4 + ipv4_header.slice().len()..]) {
+                // If parsing TCP Header is successful we can proceed
+                Ok(tcp_header) => {
+                    let data_start_index = 4 + ipv4_header.slice().len() + tcp_header.slice().len();
+
+                    let mut cm_guard = ih.manager.lock().unwrap();
+                    let cm = &mut *cm_guard;
+                    let quad = Quad {
+                        source_socket: (source_addr, tcp_header.source_port()),
+                        destination_socket: (destination_addr, tcp_header.destination_port()),
+                    };
+
+                    // Only worked out when there's a budget, so no connection pays for it otherwise
+                    let memory_room = cm.memory_room();
+
+                    // A SYN for a connection in TIME-WAIT may be the peer opening it anew,
+                    // and then the old one makes way for it
+                    let mut iss = None;
+                    if let Some(old) = cm.connections.get_mut(&quad) {
+                        if let Some(new_iss) = old.reincarnation_iss(&tcp_header) {
+                            eprintln!(
+                                "New connection attempt from {:?} in TIME-WAIT, ending the old one",
+                                quad
+                            );
+                            let state_before = old.state_name();
+                            old.end_time_wait();
+                            record_changes(
+                                &mut cm.metrics,
+                                &mut cm.observer,
+                                quad,
+                                old,
+                                state_before,
+                            );
+                            cm.connections.remove(&quad);
+                            iss = Some(new_iss);
+                        }
+                    }
+
+                    // Check for corresponding existing entry in connection hashmap, create if none exists
+                    match cm.connections.entry(quad) {
+                        // Connection exists, hand it the packet
+                        Entry::Occupied(mut connection) => {
+                            let state_before = connection.get().state_name();
+                            let was_syn_rcvd = connection.get().state() == State::SynRcvd;
+                            #[cfg(feature = "seq-trace")]
+                            let (seq_before, trigger) = (
+                                connection.get().seq_spaces(),
+                                seq_trace::describe_segment(
+                                    &tcp_header,
+                                    frame.len() - data_start_index,
+                                ),
+                            );
+                            connection.get_mut().on_packet(
+                                nic,
+                                ipv4_header,
+                                tcp_header,
+                                &frame[data_start_index..],
+                            )?;
+                            #[cfg(feature = "seq-trace")]
+                            seq_trace::log_change(
+                                &quad,
+                                &trigger,
+                                seq_before,
+                                connection.get().seq_spaces(),
+                            );
+                            connection.get().check_invariants();
+                            record_changes(
+                                &mut cm.metrics,
+                                &mut cm.observer,
+                                quad,
+                                connection.get_mut(),
+                                state_before,
+                            );
+
+                            // A connection someone else opened is ready to be accepted once the
+                            // peer has ACKed our SYN. The same segment may have taken it past
+                            // ESTABLISHED already (a FIN along with the ACK, say), but not if it
+                            // was a RST
+                            let established = was_syn_rcvd
+                                && connection.get().is_passive()
+                                && !matches!(
+                                    connection.get().state(),
+                                    State::SynRcvd | State::Closed
+                                );
+                            if established {
+                                if let Some(listener) =
+                                    cm.listeners.get_mut(&quad.destination_socket.1)
+                                {
+                                    listener.pending.push_back(quad);
+                                }
+                            }
+
+                            // Wake up anyone blocked on this connection; they'll recheck
+                            // whether there's now data to read or room to write
+                            drop(cm_guard);
+                            ih.rcv_var.notify_all();
+                            ih.snd_var.notify_all();
+                            if established {
+                                ih.pending_var.notify_all();
+                            }
+                        }
+                        // Connection does not exist, try to create it if someone is
+                        // listening on the port
+                        Entry::Vacant(entry) => {
+                            if cm.shutting_down {
+                                // No point setting up a connection that's about to be torn down
+                                eprintln!(
+                                    "Refusing connection attempt from {:?} while shutting down",
+                                    quad
+                                );
+                                return tcp::send_reset(
+                                    nic,
+                                    &ipv4_header,
+                                    &tcp_header,
+                                    &frame[data_start_index..],
+                                );
+                            }
+                            let Some(listener) =
+                                cm.listeners.get_mut(&tcp_header.destination_port())
+                            else {
+                                // Nothing's listening, so as far as we're concerned the port is
+                                // CLOSED: anything but a RST gets a RST (RFC 793 Sec. 3.9)
+                                return tcp::send_reset(
+                                    nic,
+                                    &ipv4_header,
+                                    &tcp_header,
+                                    &frame[data_start_index..],
+                                );
+                            };
+                            // Let the listener's accept filter turn the attempt down
+                            // before we create any state for it
+                            if let Some(accept_filter) = &listener.accept_filter {
+                                if tcp_header.syn() && !tcp_header.ack() && !accept_filter(&quad) {
+                                    eprintln!("Rejected connection attempt from {:?}", quad);
+                                    if listener.reject_action == RejectAction::Reset {
+                                        tcp::send_reset(
+                                            nic,
+                                            &ipv4_header,
+                                            &tcp_header,
+                                            &frame[data_start_index..],
+                                        )?;
+                                    }
+                                    return Ok(());
+                                }
+                            }
+
+                            // A RST is never answered, and there's nothing here for it to reset
+                            if tcp_header.rst() {
+                                return Ok(());
+                            }
+                            // In LISTEN, an ACK can't be for anything we sent, so it gets a RST
+                            // (RFC 793 Sec. 3.9). So does anything else that isn't a SYN, e.g.
+                            // data from a peer that lost track of its connection: RFC 793 has
+                            // those dropped, but without a RST the peer keeps retransmitting to
+                            // a connection that doesn't exist until it times out
+                            if tcp_header.ack() || !tcp_header.syn() {
+                                return tcp::send_reset(
+                                    nic,
+                                    &ipv4_header,
+                                    &tcp_header,
+                                    &frame[data_start_index..],
+                                );
+                            }
+                            // Nobody opens a connection and closes it in the same breath: SYN+FIN
+                            // is a scanner probing what we are (stacks differ in how they answer
+                            // it). Dropping it says nothing, and leaves no half-made connection
+                            // behind
+                            if tcp_header.fin() {
+                                eprintln!("Dropping SYN+FIN segment from {:?}", quad);
+                                return Ok(());
+                            }
+
+                            if let Some(connection) = tcp::Connection::accept(
+                                nic,
+                                ipv4_header,
+                                tcp_header,
+                                &frame[data_start_index..],
+                                iss.unwrap_or_else(tcp::initial_sequence_number),
+                                &cm.config,
+                                memory_room,
+                            )? {
+                                // It's up to the peer's ACK now, see above
+                                entry.insert(connection);
+                                emit(&mut cm.observer, quad, EventKind::Opened);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("An error occurred while parsing TCP packet: {:?}", e);
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("An error occurred while parsing IP packet: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+// Sends a whole IP packet out on the NIC, behind the TUN frame prefix
+fn send_packet(nic: &mut Nic, packet: &[u8]) -> io::Result<()> {
+    let mut frame = nic.buffers.take();
+    frame.extend_from_slice(&[0, 0, 0x08, 0x00]);
+    frame.extend_from_slice(packet);
+    nic.send_buffer(frame)?;
+    Ok(())
+}
+
+// Tells the observer, if there is one, about something that happened to the connection at `quad`
+fn emit(observer: &mut Option<Observer>, quad: Quad, kind: EventKind) {
+    if let Some(observer) = observer {
+        observer(&Event {
+            at: SystemTime::now(),
+            quad,
+            kind,
+        });
+    }
+}
+
+// Accounts for whatever a connection just went through (a segment, a tick) that was in
+// `state_before` until then: reports a state change to the observer, and counts a close in the
+// metrics
+fn record_changes(
+    metrics: &mut metrics::Metrics,
+    observer: &mut Option<Observer>,
+    quad: Quad,
+    connection: &mut tcp::Connection,
+    state_before: &'static str,
+) {
+    let state_after = connection.state_name();
+    if state_after != state_before {
+        emit(
+            observer,
+            quad,
+            EventKind::StateChanged {
+                from: state_before,
+                to: state_after,
+            },
+        );
+    }
+    if let Some(reason) = connection.take_close_reason() {
+        if connection.is_passive() {
+            metrics.connection_closed(quad.destination_socket.1, reason);
+        }
+        emit(observer, quad, EventKind::Closed { reason });
+    }
+}
+
+// Accepts connections on a bound port
+pub struct Listener {
+    port: u16,
+    h: InterfaceHandle,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let mut cm = self.h.manager.lock().unwrap();
+        // Connections that were never accepted have no one to hand them to, and neither do the
+        // ones still in the middle of their handshake
+        let listener = cm
+            .listeners
+            .remove(&self.port)
+            .expect("port closed while listener still active");
+        for quad in listener.pending {
+            cm.connections.remove(&quad);
+        }
+        let port = self.port;
+        cm.connections.retain(|quad, connection| {
+            !(quad.destination_socket.1 == port
+                && connection.is_passive()
+                && connection.state() == State::SynRcvd)
+        });
+    }
+}
+
+impl Listener {
+    // Blocks until a peer has connected to our port, with the handshake done, and returns a
+    // stream for the connection
+    pub fn accept(&mut self) -> io::Result<TcpStream> {
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            if let Some(quad) = cm
+                .listeners
+                .get_mut(&self.port)
+                .expect("port closed while listener still active")
+                .pending
+                .pop_front()
+            {
+                cm.metrics.connection_accepted(self.port);
+                let connection = cm.connections.get(&quad).ok_or_else(stream_terminated)?;
+                return Ok(TcpStream::new(quad, self.h.clone(), connection));
+            }
+
+            if cm.down {
+                return Err(interface_down());
+            }
+            cm = self.h.pending_var.wait(cm).unwrap();
+        }
+    }
+
+    // Only accept connections for which `filter` returns true; the others are turned down as
+    // set by set_reject_action (with a RST by default). Replaces any previous filter.
+    pub fn set_accept_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&Quad) -> bool + Send + 'static,
+    {
+        self.with_state(|listener| listener.accept_filter = Some(Box::new(filter)));
+    }
+
+    // Sets how connection attempts turned down by the accept filter are answered
+    pub fn set_reject_action(&mut self, action: RejectAction) {
+        self.with_state(|listener| listener.reject_action = action);
+    }
+
+    // Runs the discard service (RFC 863) on `port`: every connection's data is read as soon as
+    // it arrives and thrown away, and the connection is closed once the peer closes its side.
+    // Unlike a stream shut down for reading, which also throws data away, each connection is
+    // read for real, so the window reopens as fast as reads keep up and the end of the stream
+    // is noticed. Meant as a sink to measure how fast we can receive.
+    //
+    // Everything happens on background threads, one accepting and one per connection. The
+    // returned handle is the accepting one's, which runs until the interface goes down.
+    pub fn discard(iface: &Interface, port: u16) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+        let mut listener = iface.bind(port)?;
+        Ok(thread::spawn(move || loop {
+            let mut stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == io::ErrorKind::NetworkDown => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            // The biggest window we can offer, and reads that don't wait to fill up. This only
+            // fails if the connection is gone already, leaving nothing to serve
+            let marks = BufferMarks {
+                low: 1,
+                high: DISCARD_BUFFER,
+            };
+            if stream.set_recv_buffer(marks).is_err() {
+                continue;
+            }
+            thread::spawn(move || {
+                let mut buf = vec![0u8; DISCARD_BUFFER];
+                // Ok(0) is the peer's FIN, and dropping the stream sends ours. An error means
+                // the connection is gone already
+                while let Ok(n) = stream.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+        }))
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut ListenerState) -> T) -> T {
+        let mut cm = self.h.manager.lock().unwrap();
+        f(cm.listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active"))
+    }
+}
+
+// The error everything fails with once the interface is down (see Interface::wait), or has
+// been dropped
+fn interface_down() -> io::Error {
+    io::Error::new(io::ErrorKind::NetworkDown, "interface is down")
+}
+
+// The error new connections fail with once Interface::shutdown has been called
+fn shutting_down() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "interface is shutting down")
+}
+
+// The error TcpStream operations fail with once their connection is gone
+fn stream_terminated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "stream was terminated unexpectedly",
+    )
+}
+
+// The error TcpStream operations fail with once the peer has stopped responding
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+}
+
+// The error for operations on a connection that went to CLOSED other than by a graceful close
+fn closed_error(connection: &tcp::Connection) -> io::Error {
+    match connection.close_reason() {
+        Some(CloseReason::Timeout) => timed_out(),
+        _ => stream_terminated(),
+    }
+}
+
+// A single TCP connection, read and written like std::net::TcpStream
+pub struct TcpStream {
+    quad: Quad,
+    h: InterfaceHandle,
+    // The connection's receive and send buffers, which reads and writes get at without the
+    // interface lock when they can (see stream_buffers)
+    incoming: Shared<RecvBuffer>,
+    unacked: Shared<SendBuffer>,
+}
+
+impl TcpStream {
+    fn new(quad: Quad, h: InterfaceHandle, connection: &tcp::Connection) -> Self {
+        let (incoming, unacked) = connection.buffers();
+        TcpStream {
+            quad,
+            h,
+            incoming,
+            unacked,
+        }
+    }
+
+    // The connection's remote and local sockets
+    pub fn quad(&self) -> Quad {
+        self.quad
+    }
+
+    // Shuts down one or both sides of the connection. Shutting down the sending side means that
+    // once everything written so far has been sent, the peer gets our FIN, and further writes
+    // fail. Shutting down the reading side drops whatever hasn't been read yet and makes reads
+    // return Ok(0) from then on; what becomes of data the peer still sends is up to
+    // TcpConfig::read_shutdown. Either way the other side works as before.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        if let Shutdown::Read | Shutdown::Both = how {
+            connection.shutdown_read();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            connection.shutdown_write();
+        }
+        Ok(())
+    }
+
+    // Another handle to the same connection, like std's try_clone, e.g. to read on one thread
+    // and write on another. The connection closes once the last of its streams is dropped.
+    //
+    // A reader and a writer don't hold each other up: as long as there's data to read, or room
+    // to write, each only takes the lock of its own direction's buffer, not the interface lock
+    // (see stream_buffers). Only when one has to wait, or the connection has failed or been
+    // shut down, does it go through the interface lock, and then they wait on separate
+    // condition variables
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.attach();
+        Ok(TcpStream {
+            quad: self.quad,
+            h: self.h.clone(),
+            incoming: self.incoming.clone(),
+            unacked: self.unacked.clone(),
+        })
+    }
+
+    // Whether the connection could carry another exchange: still established in both
+    // directions, with nothing left to read from the last one (see ConnectionPool)
+    pub(crate) fn is_reusable(&self) -> bool {
+        let cm = self.h.manager.lock().unwrap();
+        cm.connections.get(&self.quad).is_some_and(|connection| {
+            connection.is_established()
+                && !connection.is_snd_closed()
+                && !connection.is_rd_shut()
+                && connection.recv_buffered() == 0
+        })
+    }
+
+    // The error the connection failed with (reset, or timed out), if it has failed since the
+    // last read, write or take_error reported it, like SO_ERROR. Lets code that learns of
+    // trouble some other way, e.g. from a readiness check, find out what went wrong without
+    // having to attempt I/O. Each failure is reported once
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.take_error().then(|| closed_error(connection)))
+    }
+
+    // How many bytes a read would return right now without blocking. Only data that arrived
+    // in order counts, since that's all a read can deliver
+    pub fn peek_len(&self) -> io::Result<usize> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.recv_buffered())
+    }
+
+    // The CRC-32 of everything read from the stream so far (see tcp::Connection::delivered_digest)
+    #[cfg(feature = "delivered-digest")]
+    pub fn delivered_digest(&self) -> io::Result<u32> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.delivered_digest())
+    }
+
+    // Reads urgent data the peer sent (segments with URG set, up to their urgent pointer) into
+    // `buf`, like recv with MSG_OOB. Urgent bytes never show up in `read`, and `read` never
+    // has to get past them first. Doesn't block: returns Ok(0) if no urgent data is waiting
+    pub fn read_urgent(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        let nread = cmp::min(buf.len(), connection.urgent.len());
+        for (dst, src) in buf.iter_mut().zip(connection.urgent.drain(..nread)) {
+            *dst = src;
+        }
+        Ok(nread)
+    }
+
+    // Sends everything `source` has to give, until it reaches its end, and returns how many
+    // bytes that was. `source` is read a chunk at a time, each chunk waiting to be written
+    // until the send queue has room for it (blocking like `write` does), so however much there
+    // is to send, no more than a chunk of it is ever held on top of the send queue: the
+    // sendfile way of serving a file, without reading it all into memory first.
+    //
+    // An error reading `source` ends the send with that error. What was read before it is
+    // queued already, and still delivered
+    pub fn send_from_reader<R: Read + ?Sized>(&mut self, source: &mut R) -> io::Result<u64> {
+        let mut chunk = vec![0u8; SEND_CHUNK];
+        let mut sent = 0;
+        loop {
+            let n = match source.read(&mut chunk) {
+                Ok(0) => return Ok(sent),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.write_all(&chunk[..n])?;
+            sent += n as u64;
+        }
+    }
+
+    // Blocks until the peer has acknowledged everything written so far, i.e. until it's
+    // actually received it, unlike `flush`, which returns once the data is queued. Gives up
+    // with TimedOut after `timeout`, if one is given, so a dead peer can't block us forever
+    pub fn flush_acked(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let connection = cm
+                .connections
+                .get(&self.quad)
+                .ok_or_else(stream_terminated)?;
+            // The send queue only lets go of data once it's acknowledged
+            if connection.send_queue_len() == 0 {
+                return Ok(());
+            }
+            if connection.is_closed() {
+                return Err(closed_error(connection));
+            }
+            if cm.down {
+                return Err(interface_down());
+            }
+
+            cm = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        // The connection itself is fine as far as we know, the peer's just slow
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "written data not acknowledged in time",
+                        ));
+                    }
+                    self.h.snd_var.wait_timeout(cm, left).unwrap().0
+                }
+                None => self.h.snd_var.wait(cm).unwrap(),
+            };
+        }
+    }
+
+    // Closes our sending side (like shutdown(Shutdown::Write)) and blocks until the connection
+    // is through with closing: CLOSED, or TIME-WAIT, where both sides are done and only we
+    // linger. Returns whether the close was graceful, i.e. everything we sent and our FIN got
+    // acknowledged and the peer sent its FIN, rather than the connection being reset or timing
+    // out along the way. Fails with TimedOut if it isn't through after `timeout`, which with a
+    // peer that keeps sending, or doesn't close its side, it never will be.
+    //
+    // The stream stays usable for reading until then, though anything the peer sends has to
+    // be read for it to get to its FIN if the receive window fills up
+    pub fn close_and_wait(&self, timeout: Duration) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut cm = self.h.manager.lock().unwrap();
+        cm.connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?
+            .shutdown_write();
+        loop {
+            let connection = cm
+                .connections
+                .get(&self.quad)
+                .ok_or_else(stream_terminated)?;
+            if connection.is_time_wait() {
+                return Ok(true);
+            }
+            if connection.is_closed() {
+                return Ok(connection.close_reason() == Some(CloseReason::Graceful));
+            }
+            if cm.down {
+                return Err(interface_down());
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection not closed in time",
+                ));
+            }
+            // The packet loop wakes writers on every tick, state changes included
+            cm = self.h.snd_var.wait_timeout(cm, left).unwrap().0;
+        }
+    }
+
+    // The number of bytes sent but not yet acknowledged by the peer
+    pub fn unacked_bytes(&self) -> io::Result<usize> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.unacked_bytes())
+    }
+
+    // The number of bytes written but not sent yet. Together with `unacked_bytes` this is how
+    // much of the send queue is in use, so a producer can hold off while the pipe is full
+    pub fn queued_bytes(&self) -> io::Result<usize> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.queued_bytes())
+    }
+
+    // Changes the send buffer's marks for this connection alone (see TcpConfig::send_buffer)
+    pub fn set_send_buffer(&self, marks: BufferMarks) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.set_send_buffer(marks);
+        drop(cm);
+        // A writer may be able to carry on under the new marks
+        self.h.snd_var.notify_all();
+        Ok(())
+    }
+
+    // Changes the receive buffer's marks for this connection alone (see TcpConfig::recv_buffer
+    // and tcp::Connection::set_recv_buffer)
+    pub fn set_recv_buffer(&self, marks: BufferMarks) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.set_recv_buffer(marks);
+        drop(cm);
+        self.h.rcv_var.notify_all();
+        Ok(())
+    }
+
+    // Stops the transfer in both directions until `resume`, see tcp::Connection::pause
+    pub fn pause(&self) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.pause();
+        Ok(())
+    }
+
+    // Lets a paused transfer carry on
+    pub fn resume(&self) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.resume();
+        Ok(())
+    }
+
+    // Sets the path MTU estimate for the connection, which caps the size of every segment sent
+    // from now on (see tcp::Connection::set_path_mtu)
+    pub fn set_path_mtu(&self, mtu: usize) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.set_path_mtu(mtu);
+        Ok(())
+    }
+
+    // Draws the segments the connection has sent and received as an ASCII sequence diagram (see
+    // TcpConfig::trace_segments). This keeps working after the connection has closed, for as
+    // long as the stream is held. None if the connection isn't recording its segments
+    pub fn sequence_diagram(&self) -> io::Result<Option<String>> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.sequence_diagram())
+    }
+
+    // The state the connection is in right now, e.g. State::Estab once the handshake is done
+    // or State::CloseWait once the peer has closed its side. Only a snapshot: the packet loop
+    // may move the connection on as soon as this returns
+    pub fn state(&self) -> io::Result<State> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.state())
+    }
+
+    // Caps the segment size below the negotiated MSS, like setsockopt(TCP_MAXSEG). See
+    // tcp::Connection::set_maxseg
+    pub fn set_maxseg(&self, maxseg: usize) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.set_maxseg(maxseg);
+        Ok(())
+    }
+
+    // The window scaling in effect and the windows currently in use, to tell whether a slow
+    // transfer is being held back by a window (see WindowInfo)
+    pub fn window_info(&self) -> io::Result<WindowInfo> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.window_info())
+    }
+
+    // Lets the connection send ahead of lower-priority ones (see tcp::Connection::set_priority)
+    pub fn set_priority(&self, priority: u8) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get_mut(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        connection.set_priority(priority);
+        Ok(())
+    }
+
+    pub fn priority(&self) -> io::Result<u8> {
+        let cm = self.h.manager.lock().unwrap();
+        let connection = cm
+            .connections
+            .get(&self.quad)
+            .ok_or_else(stream_terminated)?;
+        Ok(connection.priority())
+    }
+}
+
+impl Drop for TcpStream {
+    // Closes the connection gracefully: whatever has been written still gets delivered, followed
+    // by our FIN. The connection lingers in the background until the close completes.
+    //
+    // This takes the interface lock like everything the packet loop does to a connection, so
+    // a close never lands in the middle of a segment being processed: it happens either before
+    // the segment (which then finds the connection closing) or after it.
+    fn drop(&mut self) {
+        let mut cm = self.h.manager.lock().unwrap();
+        if let Some(connection) = cm.connections.get_mut(&self.quad) {
+            connection.detach();
+        }
+    }
+}
+
+impl Read for TcpStream {
+    // Blocks until there's data to read, returning Ok(0) once the peer has closed its side and
+    // everything it sent has been read
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // With enough data waiting there's nothing else to check, and the receive buffer's lock
+        // is all it takes
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.readable(buf.len()) {
+            return Ok(incoming.take(buf));
+        }
+        drop(incoming);
+
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let connection = cm
+                .connections
+                .get_mut(&self.quad)
+                .ok_or_else(stream_terminated)?;
+            if connection.is_rd_shut() {
+                return Ok(0);
+            }
+            if connection.is_closed() && !connection.is_rcv_closed() {
+                connection.take_error();
+                return Err(closed_error(connection));
+            }
+
+            // Hold out for the low-water mark, unless the peer pushed what's there or is done
+            // sending, or `buf` couldn't take that much anyway
+            let mut incoming = self.incoming.lock().unwrap();
+            if !incoming.data.is_empty()
+                && (incoming.readable(buf.len()) || connection.is_rcv_closed())
+            {
+                return Ok(incoming.take(buf));
+            }
+            drop(incoming);
+
+            if connection.is_rcv_closed() {
+                return Ok(0);
+            }
+            // What had arrived could still be read, but no more is coming
+            if cm.down {
+                return Err(interface_down());
+            }
+
+            cm = self.h.rcv_var.wait(cm).unwrap();
+        }
+    }
+}
+
+impl Write for TcpStream {
+    // Queues data for the packet loop to send, blocking while the send queue is full (or all
+    // connections together are out of memory budget)
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // With room in the send queue, and nothing that could keep it from taking more, only the
+        // send buffer's lock is needed (see SendBuffer::open)
+        let mut unacked = self.unacked.lock().unwrap();
+        if !buf.is_empty() && unacked.open && unacked.data.len() < unacked.high_water {
+            return Ok(unacked.queue(buf));
+        }
+        drop(unacked);
+
+        let mut cm = self.h.manager.lock().unwrap();
+        let mut blocked = false;
+        loop {
+            if cm.down {
+                return Err(interface_down());
+            }
+            let memory_room = cm.memory_room().unwrap_or(usize::MAX);
+            let connection = cm
+                .connections
+                .get_mut(&self.quad)
+                .ok_or_else(stream_terminated)?;
+            if connection.is_closed() {
+                connection.take_error();
+                return Err(closed_error(connection));
+            }
+            if connection.is_snd_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "connection was shut down for writing",
+                ));
+            }
+
+            // Nothing to queue, so nothing to wait for either, even with the queue full. Segments
+            // are cut from the queue, so an empty write can't end up as (or mark) one of its own
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            // Never make room by dropping anything: the queue holds data that's been sent but
+            // not yet acknowledged, which we may still have to retransmit. Once we've had to
+            // wait, we wait for the queue to drain to the low-water mark
+            let marks = connection.send_buffer();
+            let mut unacked = self.unacked.lock().unwrap();
+            let queued = unacked.data.len();
+            let has_room = queued < marks.high && (!blocked || queued <= marks.low);
+            if has_room && memory_room > 0 {
+                let nwrite = cmp::min(buf.len(), cmp::min(marks.high - queued, memory_room));
+                unacked.data.extend(&buf[..nwrite]);
+                return Ok(nwrite);
+            }
+            drop(unacked);
+
+            blocked = true;
+            cm = self.h.snd_var.wait(cm).unwrap();
+        }
+    }
+
+    // Written data is already handed to the stack, there's nothing buffered here to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+// Why a connection ended. This is a label on the close counters, so it's kept to a small,
+// fixed set of values
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(
+    feature = "json-events",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CloseReason {
+    // Both sides closed their half of the connection
+    Graceful,
+    // Either side reset the connection
+    Reset,
+    // We gave up on a peer that stopped responding
+    Timeout,
+}
+
+// A snapshot of one connection's sequence spaces and buffers
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "json-events", derive(serde::Serialize))]
+pub struct ConnectionStats {
+    pub state: &'static str,
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    pub snd_wnd: u16,
+    pub rcv_nxt: u32,
+    pub rcv_wnd: u16,
+    // Bytes sent but not acknowledged yet
+    pub bytes_in_flight: u32,
+    // Bytes queued by the application and not acknowledged yet (including those in flight)
+    pub send_queued: usize,
+    // Bytes received and waiting for the application to read them
+    pub recv_buffered: usize,
+    pub rto_ms: u128,
+    // The congestion control algorithm in use, and its current window in bytes
+    pub congestion_control: &'static str,
+    pub cwnd: usize,
+    // Duplicate ACKs received in a row, see tcp::Connection::on_duplicate_ack
+    pub dup_acks: u32,
+    // Throughput and goodput, in both directions. What we put on the wire (bytes_sent) counts
+    // every retransmission again, while bytes_acked counts each byte the peer got once: that's
+    // the goodput. Likewise bytes_received counts everything the peer sent us, duplicates and
+    // out-of-order segments we dropped included, and bytes_delivered only what went into the
+    // stream. All of them count payload only, not headers
+    pub bytes_sent: u64,
+    pub bytes_retransmitted: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub bytes_delivered: u64,
+}
+
+impl ConnectionStats {
+    // The share of the bytes we sent that were retransmissions, from 0 (none) to 1. 0 too
+    // before anything has been sent
+    pub fn retransmission_ratio(&self) -> f64 {
+        if self.bytes_sent == 0 {
+            return 0.0;
+        }
+        self.bytes_retransmitted as f64 / self.bytes_sent as f64
+    }
+}
+
+// The window scaling in effect on a connection, and the windows it's using right now (see
+// TcpStream::window_info). The scales are shift counts, 0 meaning the window field is taken as
+// is. We don't do window scaling, so they're always 0 here and neither window goes past 64KiB,
+// whatever the peer offered: peer_offered_scale shows when a peer was ready for more
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WindowInfo {
+    // Applied to the windows the peer advertises
+    pub send_scale: u8,
+    // Applied to the windows we advertise
+    pub recv_scale: u8,
+    // The Window Scale option on the peer's SYN, if it had one
+    pub peer_offered_scale: Option<u8>,
+    // How much the peer lets us have outstanding, and how much we let it, in bytes, as of the
+    // last segment each way
+    pub send_window: usize,
+    pub recv_window: usize,
+}
+
+// Aggregate counters for one listening port. They only ever go up, and outlive the Listener
+// itself, so a service can be rebound without its numbers starting over
+#[derive(Clone, Debug, Default)]
+pub struct ListenerMetrics {
+    // Connections handed out by Listener::accept
+    pub accepted: u64,
+    pub closed_graceful: u64,
+    pub closed_reset: u64,
+    pub closed_timeout: u64,
+}
+
+impl ListenerMetrics {
+    // The number of connections that have ended for the given reason
+    pub fn closed(&self, reason: CloseReason) -> u64 {
+        match reason {
+            CloseReason::Graceful => self.closed_graceful,
+            CloseReason::Reset => self.closed_reset,
+            CloseReason::Timeout => self.closed_timeout,
+        }
+    }
+}
+
+// The counters of every port anyone has listened on, labeled by port
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) listeners: HashMap<u16, ListenerMetrics>,
+}
+
+impl Metrics {
+    pub(crate) fn connection_accepted(&mut self, port: u16) {
+        self.listeners.entry(port).or_default().accepted += 1;
+    }
+
+    pub(crate) fn connection_closed(&mut self, port: u16, reason: CloseReason) {
+        let listener = self.listeners.entry(port).or_default();
+        match reason {
+            CloseReason::Graceful => listener.closed_graceful += 1,
+            CloseReason::Reset => listener.closed_reset += 1,
+            CloseReason::Timeout => listener.closed_timeout += 1,
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use crate::buffer_pool::BufferPool;
+
+// The TUN device, with an optional artificial delay on everything we send through it (see
+// Interface::set_artificial_delay). Delayed frames wait in a queue until their time comes and
+// the packet loop flushes them, so what the peer sees is a link with a fixed, long round trip;
+// nothing is dropped or reordered.
+//
+// A Nic can also have no device at all and just keep what's sent through it, for handing
+// frames to the stack directly (see handle_frame) and seeing what it answers.
+pub(crate) struct Nic {
+    device: Device,
+    delay: Duration,
+    // Frames held back, with when each may go out (and how to cut them up, if they hold several
+    // segments). Oldest first, and since the delay is the same for all of them, also in order
+    // of their deadlines
+    delayed: VecDeque<(Instant, Vec<u8>, Option<Segmentation>)>,
+    // The interface's packet buffers, for the delayed frames and everyone sending through us
+    pub(crate) buffers: BufferPool,
+}
+
+enum Device {
+    Tun(tun_tap::Iface),
+    // The TUN device opened with IFF_VNET_HDR, which tun_tap has no way to ask for: between the
+    // frame prefix and the packet, every frame carries a virtio-net header (struct
+    // virtio_net_hdr in <linux/virtio_net.h>). Ours can ask the kernel to cut a frame into
+    // segments (see send_segments); the kernel's, on frames it gives us, we skip
+    Vnet(OwnedFd),
+    // Every frame sent so far, oldest first, until someone takes them
+    Capture(Vec<Vec<u8>>),
+}
+
+// How a frame of several segments is cut back into packets: its IP and TCP headers go in front
+// of every `segment_size` bytes of its payload, with the sequence numbers, lengths and
+// checksums fixed up for each (the last segment gets whatever payload is left over)
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Segmentation {
+    // Length of the IP header, which the TCP header follows
+    pub(crate) ip_header_len: u16,
+    // Length of the IP and TCP headers together
+    pub(crate) header_len: u16,
+    pub(crate) segment_size: u16,
+}
+
+// Size of struct virtio_net_hdr, the header the kernel expects unless told otherwise with
+// TUNSETVNETHDRSZ
+const VNET_HDR_LEN: usize = 10;
+// virtio_net_hdr flags: the kernel is to finish the checksum, starting at csum_start
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+// virtio_net_hdr gso_type: TCP over IPv4
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+// Where the checksum sits in a TCP header
+const TCP_CHECKSUM_OFFSET: u16 = 16;
+
+impl Nic {
+    // Creates the TUN device `name`. If `segmentation_offload` is set (see
+    // TcpConfig::segmentation_offload), it's opened so that several segments can go out in one
+    // write; should the kernel turn that down, it's opened as usual, one segment per write
+    pub(crate) fn open(name: &str, segmentation_offload: bool) -> io::Result<Self> {
+        if segmentation_offload {
+            match open_vnet_tun(name) {
+                Ok(fd) => return Ok(Self::with_device(Device::Vnet(fd))),
+                Err(e) => eprintln!(
+                    "Can't open {} with virtio-net headers ({}), sending one segment per write",
+                    name, e
+                ),
+            }
+        }
+        let iface = tun_tap::Iface::new(name, tun_tap::Mode::Tun)?;
+        Ok(Self::with_device(Device::Tun(iface)))
+    }
+
+    // A Nic without a device, which keeps every frame sent through it for `take_captured`
+    pub(crate) fn capture() -> Self {
+        Self::with_device(Device::Capture(Vec::new()))
+    }
+
+    fn with_device(device: Device) -> Self {
+        Nic {
+            device,
+            delay: Duration::ZERO,
+            delayed: VecDeque::new(),
+            buffers: BufferPool::new(),
+        }
+    }
+
+    // Whether send_segments can be used, i.e. the kernel cuts up frames for us
+    pub(crate) fn segments_offloaded(&self) -> bool {
+        matches!(self.device, Device::Vnet(_))
+    }
+
+    // The frames sent so far, if there's no device they went out on (empty otherwise)
+    pub(crate) fn take_captured(&mut self) -> Vec<Vec<u8>> {
+        match &mut self.device {
+            Device::Tun(_) | Device::Vnet(_) => Vec::new(),
+            Device::Capture(frames) => mem::take(frames),
+        }
+    }
+
+    // Puts a frame on the wire right away, whatever the delay
+    fn transmit(&mut self, frame: &[u8], segmentation: Option<Segmentation>) -> io::Result<usize> {
+        match &mut self.device {
+            Device::Tun(iface) => iface.send(frame),
+            Device::Vnet(fd) => {
+                let header = vnet_header(segmentation);
+                let parts = [&frame[..4], &header[..], &frame[4..]];
+                let written = writev(fd.as_raw_fd(), parts)?;
+                Ok(written.saturating_sub(VNET_HDR_LEN))
+            }
+            Device::Capture(frames) => {
+                frames.push(frame.to_vec());
+                Ok(frame.len())
+            }
+        }
+    }
+
+    // Sends a frame, or queues it to go out once the delay is up
+    pub(crate) fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        // While earlier frames are still queued, this one waits behind them even if the delay
+        // has just been turned off, so frames never overtake each other
+        if self.delay.is_zero() && self.delayed.is_empty() {
+            return self.transmit(frame, None);
+        }
+        let mut buf = self.buffers.take();
+        buf.extend_from_slice(frame);
+        self.delayed
+            .push_back((Instant::now() + self.delay, buf, None));
+        Ok(frame.len())
+    }
+
+    // Like `send`, for a frame built in one of the pool's buffers, which goes back to the pool
+    // once the frame is out
+    pub(crate) fn send_buffer(&mut self, frame: Vec<u8>) -> io::Result<usize> {
+        self.send_owned(frame, None)
+    }
+
+    // Like `send_buffer`, for a frame holding several segments' worth of payload behind a
+    // single IP and TCP header, which the kernel cuts up as `segmentation` says. The TCP
+    // checksum field is to hold the sum of the pseudo-header only: the kernel works out the
+    // rest for each segment. Only for a Nic whose segments_offloaded() says so
+    pub(crate) fn send_segments(
+        &mut self,
+        frame: Vec<u8>,
+        segmentation: Segmentation,
+    ) -> io::Result<usize> {
+        debug_assert!(self.segments_offloaded());
+        self.send_owned(frame, Some(segmentation))
+    }
+
+    fn send_owned(
+        &mut self,
+        frame: Vec<u8>,
+        segmentation: Option<Segmentation>,
+    ) -> io::Result<usize> {
+        if self.delay.is_zero() && self.delayed.is_empty() {
+            let sent = self.transmit(&frame, segmentation);
+            self.buffers.give(frame);
+            return sent;
+        }
+        let len = frame.len();
+        self.delayed
+            .push_back((Instant::now() + self.delay, frame, segmentation));
+        Ok(len)
+    }
+
+    pub(crate) fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.device {
+            Device::Tun(iface) => iface.recv(buf),
+            Device::Vnet(fd) => {
+                // The kernel's virtio-net header goes to a buffer of its own (we have no use for
+                // it), so the frame comes out in `buf` as it would without one
+                let mut header = [0u8; VNET_HDR_LEN];
+                let (prefix, packet) = buf.split_at_mut(4);
+                let read = readv(fd.as_raw_fd(), [prefix, &mut header[..], packet])?;
+                // Anything short of a whole header has no packet after it, just like a frame
+                // that ends with its prefix
+                Ok(if read <= 4 {
+                    read
+                } else {
+                    cmp::max(read.saturating_sub(VNET_HDR_LEN), 4)
+                })
+            }
+            Device::Capture(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no device to receive from",
+            )),
+        }
+    }
+
+    // Sends every queued frame whose delay is up
+    pub(crate) fn flush_delayed(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        while let Some((due, _, _)) = self.delayed.front() {
+            if *due > now {
+                break;
+            }
+            let (_, frame, segmentation) = self.delayed.pop_front().unwrap();
+            self.transmit(&frame, segmentation)?;
+            self.buffers.give(frame);
+        }
+        Ok(())
+    }
+
+    // Changes the delay for frames sent from now on. Frames already queued keep theirs
+    pub(crate) fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+}
+
+impl AsRawFd for Nic {
+    // Without a device there's nothing to poll, and a negative fd is one poll skips
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.device {
+            Device::Tun(iface) => iface.as_raw_fd(),
+            Device::Vnet(fd) => fd.as_raw_fd(),
+            Device::Capture(_) => -1,
+        }
+    }
+}
+
+// Opens /dev/net/tun as the TUN device `name`, in IFF_VNET_HDR mode but otherwise like tun_tap
+// does it: with the 4 byte flags + protocol prefix in front of every frame
+fn open_vnet_tun(name: &str) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    // Leaving the last byte for the terminating NUL
+    for (to, from) in ifr.ifr_name[..libc::IFNAMSIZ - 1]
+        .iter_mut()
+        .zip(name.bytes())
+    {
+        *to = from as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_VNET_HDR) as libc::c_short;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+// Our virtio-net header for a frame: all zeros for one that's a single, finished packet, or the
+// instructions for cutting it up. Like all legacy virtio fields, the header's are in the host's
+// byte order
+fn vnet_header(segmentation: Option<Segmentation>) -> [u8; VNET_HDR_LEN] {
+    let mut header = [0u8; VNET_HDR_LEN];
+    if let Some(segmentation) = segmentation {
+        header[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        header[1] = VIRTIO_NET_HDR_GSO_TCPV4;
+        header[2..4].copy_from_slice(&segmentation.header_len.to_ne_bytes());
+        header[4..6].copy_from_slice(&segmentation.segment_size.to_ne_bytes());
+        // The checksum to finish is the TCP header's, which covers the rest of the packet
+        header[6..8].copy_from_slice(&segmentation.ip_header_len.to_ne_bytes());
+        header[8..10].copy_from_slice(&TCP_CHECKSUM_OFFSET.to_ne_bytes());
+    }
+    header
+}
+
+// Writes the parts one after the other, as a single frame
+fn writev(fd: RawFd, parts: [&[u8]; 3]) -> io::Result<usize> {
+    let iovecs = parts.map(|part| libc::iovec {
+        iov_base: part.as_ptr() as *mut libc::c_void,
+        iov_len: part.len(),
+    });
+    let written = unsafe { libc::writev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(written as usize)
+}
+
+// Reads a single frame, filling the parts one after the other
+fn readv(fd: RawFd, parts: [&mut [u8]; 3]) -> io::Result<usize> {
+    let iovecs = parts.map(|part| libc::iovec {
+        iov_base: part.as_mut_ptr() as *mut libc::c_void,
+        iov_len: part.len(),
+    });
+    let read = unsafe { libc::readv(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(read as usize)
+}
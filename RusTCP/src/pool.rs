@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::{Interface, TcpStream};
+
+// Most idle connections kept per remote by default
+const DEFAULT_MAX_IDLE_PER_REMOTE: usize = 8;
+
+// Keeps established client connections around between uses, like HTTP keep-alive one level
+// down: a client making many short exchanges with the same server takes a connection with
+// `get`, does its request and response over it, and hands it back with `put` instead of
+// dropping it. The next `get` for that server picks it up again, saving the handshake (and the
+// ephemeral port, and TIME-WAIT at the end) that a fresh connection would cost.
+//
+// Reuse only works if the application protocol can carry several exchanges over one
+// connection and knows where each one ends, e.g. with MessageStream framing. The pool can't
+// tell whether a response has been read in full; it only checks that nothing is left unread
+// when a connection comes back.
+pub struct ConnectionPool<'a> {
+    iface: &'a Interface,
+    // How long a connection may sit in the pool before it's closed
+    idle_timeout: Duration,
+    max_idle_per_remote: usize,
+    // Idle connections per remote, most recently returned last
+    idle: HashMap<(Ipv4Addr, u16), Vec<(TcpStream, Instant)>>,
+}
+
+impl<'a> ConnectionPool<'a> {
+    // A pool opening its connections on `iface`, closing those idle for longer than
+    // `idle_timeout`
+    pub fn new(iface: &'a Interface, idle_timeout: Duration) -> Self {
+        ConnectionPool {
+            iface,
+            idle_timeout,
+            max_idle_per_remote: DEFAULT_MAX_IDLE_PER_REMOTE,
+            idle: HashMap::new(),
+        }
+    }
+
+    // Caps how many idle connections are kept for each remote. Connections handed back beyond
+    // that are closed right away
+    pub fn set_max_idle_per_remote(&mut self, max: usize) {
+        self.max_idle_per_remote = max;
+        for streams in self.idle.values_mut() {
+            let excess = streams.len().saturating_sub(max);
+            streams.drain(..excess);
+        }
+    }
+
+    // An established connection to `remote`: an idle one from the pool if there's one still
+    // good, otherwise a new one (see Interface::connect)
+    pub fn get(&mut self, remote: (Ipv4Addr, u16)) -> io::Result<TcpStream> {
+        self.evict_idle();
+        if let Some(streams) = self.idle.get_mut(&remote) {
+            // The most recently used connection is the least likely to have been closed by the
+            // server in the meantime
+            while let Some((stream, _)) = streams.pop() {
+                if stream.is_reusable() {
+                    return Ok(stream);
+                }
+            }
+        }
+        self.iface.connect(remote)
+    }
+
+    // Hands a connection back for reuse. It's only kept if it's still fully open with nothing
+    // left unread; anything else (the server closed it, or a response wasn't read to the end)
+    // is closed instead, since the next user couldn't tell where their own data starts
+    pub fn put(&mut self, stream: TcpStream) {
+        if !stream.is_reusable() || self.max_idle_per_remote == 0 {
+            return;
+        }
+        let streams = self.idle.entry(stream.quad().source_socket).or_default();
+        if streams.len() >= self.max_idle_per_remote {
+            // Make room by closing the one that's been idle longest
+            streams.remove(0);
+        }
+        streams.push((stream, Instant::now()));
+    }
+
+    // Closes every pooled connection that has been idle for longer than the idle timeout, or
+    // that the server has closed while in the pool. `get` does this too, so calling it is only
+    // needed to let go of connections sooner on a pool that isn't being used
+    pub fn evict_idle(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        for streams in self.idle.values_mut() {
+            streams
+                .retain(|(stream, since)| since.elapsed() < idle_timeout && stream.is_reusable());
+        }
+        self.idle.retain(|_, streams| !streams.is_empty());
+    }
+
+    // The number of idle connections in the pool
+    pub fn idle_count(&self) -> usize {
+        self.idle.values().map(Vec::len).sum()
+    }
+}
@@ -0,0 +1,56 @@
+use std::io;
+
+use crate::ip_id;
+use crate::nic::Nic;
+use crate::tcp;
+use crate::Quad;
+
+// Everything that goes into a hand-crafted segment, see Interface::send_raw_segment.
+// Nothing here is checked against any connection's state: whatever is asked for goes out.
+#[derive(Clone, Debug, Default)]
+pub struct SegmentSpec {
+    pub seq: u32,
+    pub ack_number: u32,
+    pub window: u16,
+    pub urgent_pointer: u16,
+
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+    pub urg: bool,
+    pub ece: bool,
+    pub cwr: bool,
+
+    pub payload: Vec<u8>,
+}
+
+// Sends the segment described by `spec` from our side of `quad` to the peer's, with correct
+// IP framing and checksums
+pub(crate) fn send(nic: &mut Nic, quad: &Quad, spec: &SegmentSpec) -> io::Result<()> {
+    let (peer_addr, peer_port) = quad.source_socket;
+    let (local_addr, local_port) = quad.destination_socket;
+
+    let mut tcp = etherparse::TcpHeader::new(local_port, peer_port, spec.seq, spec.window);
+    tcp.acknowledgment_number = spec.ack_number;
+    tcp.urgent_pointer = spec.urgent_pointer;
+    tcp.syn = spec.syn;
+    tcp.ack = spec.ack;
+    tcp.fin = spec.fin;
+    tcp.rst = spec.rst;
+    tcp.psh = spec.psh;
+    tcp.urg = spec.urg;
+    tcp.ece = spec.ece;
+    tcp.cwr = spec.cwr;
+
+    let mut ip = etherparse::Ipv4Header::new(
+        0,
+        64,
+        etherparse::IpNumber::Tcp as u8,
+        local_addr.octets(),
+        peer_addr.octets(),
+    );
+    ip.identification = ip_id::next_stateless();
+    tcp::send_segment(nic, &mut ip, &mut tcp, &spec.payload)
+}
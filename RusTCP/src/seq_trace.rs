@@ -0,0 +1,67 @@
+// Logs every change to a connection's sequence spaces, with what caused it (the seq-trace
+// feature). Far noisier than anything else the stack logs: a bulk transfer logs a line or two
+// per segment each way. It's for chasing off-by-ones in the sequence arithmetic, where the
+// thing to look for is the one step where a number moves by one more or less than it should.
+//
+// The packet loop takes a snapshot before handing a connection a segment or a tick, and
+// another after, and logs the difference. Without the feature none of this is compiled in.
+use crate::Quad;
+
+// The variables of both sequence spaces that segments move (RFC 793 Sec. 3.2)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SeqSpaces {
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    pub snd_wnd: u16,
+    pub rcv_nxt: u32,
+    pub rcv_wnd: u16,
+}
+
+// What a received segment looks like, as the trigger of a change
+pub(crate) fn describe_segment(tcp: &etherparse::TcpHeaderSlice, payload_len: usize) -> String {
+    let flags: Vec<&str> = [
+        (tcp.syn(), "SYN"),
+        (tcp.fin(), "FIN"),
+        (tcp.rst(), "RST"),
+        (tcp.psh(), "PSH"),
+        (tcp.ack(), "ACK"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, name)| *name)
+    .collect();
+    format!(
+        "segment {} seq={} ack={} len={} win={}",
+        flags.join(","),
+        tcp.sequence_number(),
+        tcp.acknowledgment_number(),
+        payload_len,
+        tcp.window_size()
+    )
+}
+
+// Logs the variables that differ between `before` and `after`, as old->new, if any do
+pub(crate) fn log_change(quad: &Quad, trigger: &str, before: SeqSpaces, after: SeqSpaces) {
+    if before == after {
+        return;
+    }
+    let fields = [
+        ("SND.UNA", before.snd_una, after.snd_una),
+        ("SND.NXT", before.snd_nxt, after.snd_nxt),
+        ("SND.WND", before.snd_wnd as u32, after.snd_wnd as u32),
+        ("RCV.NXT", before.rcv_nxt, after.rcv_nxt),
+        ("RCV.WND", before.rcv_wnd as u32, after.rcv_wnd as u32),
+    ];
+    let changes: Vec<String> = fields
+        .iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(name, old, new)| format!("{}={}->{}", name, old, new))
+        .collect();
+    eprintln!(
+        "[seq] {}:{} after {}: {}",
+        quad.source_socket.0,
+        quad.source_socket.1,
+        trigger,
+        changes.join(" ")
+    );
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "delivered-digest")]
+use crate::digest::Crc32;
+
+// A connection's two buffers, the one it receives into and the one it sends from, each behind
+// a lock of its own. The connection itself lives behind the interface's lock, with everything
+// else the packet loop works on; the buffers are shared with the connection's TcpStreams,
+// which reach them without going through that lock.
+//
+// That's what lets a reader and a writer on the same connection (see TcpStream::try_clone), or
+// either of them and the packet loop, get on with it side by side: a read that finds data
+// waiting only takes the receive buffer's lock to copy it out, and a write that finds room only
+// the send buffer's to copy it in. Anything else (waiting, errors, or a check the buffer can't
+// answer on its own) goes the long way round, through the interface's lock, which is also the
+// one the condition variables are tied to.
+//
+// To never have two threads wait on each other, the locks are only ever taken in one order:
+// the interface's first (if at all), then a buffer's. No one holds both buffers at once.
+pub(crate) type Shared<T> = Arc<Mutex<T>>;
+
+// Data that has arrived in order and the application hasn't read yet, along with a copy of
+// what a read needs to know to take it without asking the connection
+#[derive(Default)]
+pub(crate) struct RecvBuffer {
+    pub(crate) data: VecDeque<u8>,
+    // How much of `data`, from the front, the peer pushed (sent with PSH). Reads hand pushed
+    // data over without holding out for the low-water mark
+    pub(crate) pushed: usize,
+    // TcpConfig::recv_buffer's low-water mark and TcpConfig::ignore_psh, as the connection has
+    // them
+    pub(crate) low_water: usize,
+    pub(crate) ignore_psh: bool,
+    // The connection failed (was reset, or timed out): a read is to report that, rather than
+    // hand over what's still buffered
+    pub(crate) failed: bool,
+    // Running CRC of the bytes the application has read
+    #[cfg(feature = "delivered-digest")]
+    pub(crate) digest: Crc32,
+}
+
+impl RecvBuffer {
+    // Whether a read into a buffer of `want` bytes can take data right away, with nothing else
+    // to check: there's enough buffered for the low-water mark (or for `want`), or the peer
+    // pushed some of it
+    pub(crate) fn readable(&self, want: usize) -> bool {
+        let low_water = self.low_water.min(want).max(1);
+        !self.failed
+            && !self.data.is_empty()
+            && (self.data.len() >= low_water || (self.pushed > 0 && !self.ignore_psh))
+    }
+
+    // Moves as much data as fits into `buf`, returning how much that was
+    pub(crate) fn take(&mut self, buf: &mut [u8]) -> usize {
+        // From both halves of the ring buffer
+        let (head, tail) = self.data.as_slices();
+        let mut nread = head.len().min(buf.len());
+        buf[..nread].copy_from_slice(&head[..nread]);
+        let tread = tail.len().min(buf.len() - nread);
+        buf[nread..nread + tread].copy_from_slice(&tail[..tread]);
+        nread += tread;
+        self.data.drain(..nread);
+        self.pushed = self.pushed.saturating_sub(nread);
+        #[cfg(feature = "delivered-digest")]
+        self.digest.update(&buf[..nread]);
+        nread
+    }
+}
+
+// Data the application has written and the peer hasn't acknowledged yet: the part from SND.UNA
+// on has been sent, the rest is waiting to be. Along with it goes a copy of what a write needs
+// to know to queue more without asking the connection
+#[derive(Default)]
+pub(crate) struct SendBuffer {
+    pub(crate) data: VecDeque<u8>,
+    // TcpConfig::send_buffer's high-water mark, as the connection has it
+    pub(crate) high_water: usize,
+    // Writes may be queued as they come. Once the connection is closed, shut down for writing,
+    // or on an interface that's down, they no longer may, and a write has to find out from the
+    // connection what to report instead. Nor under a memory budget (see
+    // Interface::set_memory_budget), which only the interface can tell whether there's room in
+    pub(crate) open: bool,
+}
+
+impl SendBuffer {
+    // Queues as much of `buf` as fits below the high-water mark, returning how much that was
+    pub(crate) fn queue(&mut self, buf: &[u8]) -> usize {
+        let nwrite = buf
+            .len()
+            .min(self.high_water.saturating_sub(self.data.len()));
+        self.data.extend(&buf[..nwrite]);
+        nwrite
+    }
+}
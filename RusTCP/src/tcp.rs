@@ -1,3 +1,59 @@
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::buffer_pool::BufferPool;
+use crate::config::{
+    BufferMarks, ReadShutdown, TcpConfig, TimeWaitRst, TimeWaitSyn, UrgentPointer,
+};
+use crate::congestion::{self, CongestionControl};
+use crate::interval::SeqIntervalSet;
+use crate::ip_id::{self, IpIds};
+use crate::metrics::{CloseReason, ConnectionStats, WindowInfo};
+use crate::nic::{Nic, Segmentation};
+#[cfg(feature = "seq-trace")]
+use crate::seq_trace::SeqSpaces;
+use crate::stream_buffers::{RecvBuffer, SendBuffer, Shared};
+use crate::trace::{SegmentSummary, SegmentTrace};
+
+// MSS to assume when the peer's SYN doesn't carry the option (RFC 1122 Sec. 4.2.2.6)
+pub(crate) const DEFAULT_MSS: usize = 536;
+
+// MTU of the TUN link, and so the biggest IP packet we ever build
+const LINK_MTU: usize = 1500;
+
+// Smallest MTU every IPv4 link must support (RFC 791); path MTU estimates are never taken below it
+const MIN_PATH_MTU: usize = 68;
+
+// Largest Window Scale shift there is (RFC 7323 Sec. 2.3): windows up to 2^30 bytes, which keeps
+// them below half the sequence space
+const MAX_WINDOW_SCALE: u8 = 14;
+
+// Retransmission timeout to start out with (RFC 6298 Sec. 2.1), and the most it backs off to
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+// How many duplicate ACKs in a row make us take the oldest segment for lost and retransmit it
+// without waiting for the timer (fast retransmit, RFC 5681 Sec. 3.2)
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+// ECN codepoints in the IP header (RFC 3168 Sec. 5)
+const ECN_ECT0: u8 = 0b10;
+const ECN_CE: u8 = 0b11;
+
+// How many times a peer may get ECN signalling wrong before we stop using ECN with it
+const ECN_MAX_VIOLATIONS: u32 = 8;
+
+// Longest we hold back the ACK for data received, hoping to piggyback it on data of our own or
+// cover more segments with it (RFC 1122 Sec. 4.2.3.2 allows up to 500ms)
+const DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(40);
+
 // TCP State Transition Diagram (RFC 793)
 // =====================================
 //
@@ -60,7 +116,7 @@
 // State Descriptions:
 // ------------------
 // CLOSED      - No connection exists
-// LISTEN      - Server waiting for connection requests  
+// LISTEN      - Server waiting for connection requests
 // SYN-SENT    - Client has sent SYN, waiting for SYN-ACK
 // SYN-RCVD    - Server received SYN, sent SYN-ACK, waiting for ACK
 // ESTABLISHED - Connection is open, data transfer can occur
@@ -78,14 +134,22 @@
 // - Simultaneous open: CLOSED -> SYN-SENT -> SYN-RCVD -> ESTABLISHED
 // - Simultaneous close: ESTABLISHED -> FIN-WAIT-1 -> CLOSING -> TIME-WAIT -> CLOSED
 
-
-
 // Each state represents a specific stage in the TCP connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum State {
     Closed,
+    // No connection is ever in LISTEN: listening happens per port, by a bound Listener, before
+    // there's a TCB for anything. Segments to ports without one get the CLOSED treatment
     Listen,
+    SynSent,
     SynRcvd,
     Estab,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
 }
 
 pub struct Connection {
@@ -95,7 +159,158 @@ pub struct Connection {
     // Keeps track of sequence numbers (packet ordering label) for data we're receiving
     recv: RecvSequenceSpace,
     ip: etherparse::Ipv4Header,
+    // Picks the identification of each packet we send from `ip`
+    ip_ids: IpIds,
     tcp: etherparse::TcpHeader,
+
+    // Data received in order, waiting for the application to read it. Its `pushed` is how much
+    // of the front of it the peer pushed: everything up to the end of the last segment with PSH
+    // set (RFC 1122 Sec. 4.2.2.2). Shared with the connection's TcpStreams, see stream_buffers
+    pub(crate) incoming: Shared<RecvBuffer>,
+    // Urgent data, taken out of the stream and waiting for TcpStream::read_urgent
+    pub(crate) urgent: VecDeque<u8>,
+    // Data that arrived out of order, beyond a hole at RCV.NXT, held until the hole is filled
+    // (see `hold_out_of_order`). `held` is which sequence ranges we have, `held_data` the
+    // pieces themselves as (sequence number, bytes, whether their segment had PSH set). No two
+    // pieces overlap
+    held: SeqIntervalSet,
+    held_data: Vec<(u32, Vec<u8>, bool)>,
+    // Data the application wants sent, starting at SND.UNA: the first SND.NXT - SND.UNA bytes
+    // are in flight (sent but not acknowledged), the rest hasn't been sent yet. Shared like
+    // `incoming`
+    pub(crate) unacked: Shared<SendBuffer>,
+
+    // Largest segment payload the peer is willing to receive, from the MSS option on its SYN
+    peer_mss: usize,
+    // The shift count of the Window Scale option on the peer's SYN, if it had one. Only kept
+    // for window_info: we never offer scaling ourselves, so it stays off either way
+    peer_window_scale: Option<u8>,
+    // Current estimate of the path MTU to the peer, if we have one. We always send with the
+    // Don't Fragment bit set, so no packet may be larger than this
+    path_mtu: Option<usize>,
+    // Our own cap on segment size, set by the application (like TCP_MAXSEG). Only ever shrinks
+    // segments below what the peer and the path allow
+    maxseg: Option<usize>,
+    // Where the connection goes in the packet loop's round of sending, see set_priority
+    priority: u8,
+
+    // The segments we sent and received, if the connection records them (see
+    // TcpConfig::trace_segments)
+    trace: Option<SegmentTrace>,
+
+    // When the retransmission timer goes off, if it's running. It runs whenever something we sent
+    // is still unacknowledged
+    rto_deadline: Option<Instant>,
+    // Current retransmission timeout, doubled every time the timer goes off (RFC 6298 Sec. 5.5)
+    rto: Duration,
+    // How many times in a row the timer has gone off without the peer acknowledging anything
+    retransmits: u32,
+    // When the persist timer goes off, if it's running. It runs while the peer's window is
+    // closed and we have data waiting for it to open, see `persist`
+    persist_deadline: Option<Instant>,
+    // Current time between window probes, backed off like the RTO
+    persist_interval: Duration,
+
+    // ECN bookkeeping, if ECN was negotiated on the handshake and hasn't been given up on since
+    ecn: Option<Ecn>,
+
+    // Limits how much we have in flight on top of the peer's window
+    cc: Box<dyn CongestionControl>,
+    // F-RTO's progress after a retransmission timeout, when enabled (TcpConfig::frto)
+    frto: Option<Frto>,
+    // Duplicate ACKs in a row, see on_duplicate_ack
+    dup_acks: u32,
+    // SND.NXT as of the last fast retransmit, until everything up to it has been acknowledged.
+    // Until then, more duplicate ACKs are for the same loss, and mustn't cut the congestion
+    // window again (RFC 6582 Sec. 3.2)
+    recover: Option<u32>,
+
+    // We owe the peer an ACK for segments received since the last one we sent
+    ack_pending: bool,
+    // The pending ACK mustn't be delayed, e.g. because it's for a FIN or out-of-order data
+    ack_now: bool,
+    // In-order data received since our last ACK, see `flush_ack`
+    unacked_rcv_bytes: usize,
+    // When the delayed ACK timer goes off, if it's running
+    ack_deadline: Option<Instant>,
+    // Set by `pause`: we hold off sending anything new, and show the peer a closed window
+    paused: bool,
+    // The application won't read anymore, see `shutdown_read`
+    rd_shut: bool,
+    // An error the application hasn't been told about yet: set when the connection is reset or
+    // times out, and cleared once a read, a write or take_error has reported it (like SO_ERROR)
+    error_pending: bool,
+    // The application is done sending: a FIN goes out once everything queued before it has
+    fin_queued: bool,
+    // How many TcpStreams refer to the connection (see TcpStream::try_clone). It starts out at
+    // one, for the stream that accept or connect hands out
+    streams: usize,
+    // No TcpStream refers to the connection anymore, so it goes away once it's CLOSED
+    detached: bool,
+    // When TIME-WAIT ends, if we're in it
+    time_wait_deadline: Option<Instant>,
+    // When we last heard from the peer, and how many keep-alive probes have gone unanswered
+    // since, see `keepalive`
+    last_heard: Instant,
+    keepalive_probes_sent: u32,
+    // When the current second of challenge ACKs began, and how many have gone out in it, see
+    // challenge_ack_allowed
+    challenge_acks_since: Instant,
+    challenge_acks: u32,
+
+    config: TcpConfig,
+
+    // Whether the connection came in through a listener, as opposed to us opening it
+    passive: bool,
+    // Why the connection went to CLOSED, if it has
+    close_reason: Option<CloseReason>,
+    // Whether the packet loop has counted the close in the metrics yet
+    close_counted: bool,
+
+    // Payload bytes sent, retransmissions included, and how many of them were retransmissions
+    bytes_sent: u64,
+    bytes_retransmitted: u64,
+    // Bytes the peer has acknowledged, each counted once however often it was sent
+    bytes_acked: u64,
+    // Payload bytes received, duplicates and segments we had to drop included
+    bytes_received: u64,
+    // Bytes received in order for the first time, i.e. what was actually added to the stream
+    bytes_delivered: u64,
+}
+
+// Where we are in telling a spurious retransmission timeout from a real one, with F-RTO
+// (RFC 5682 Sec. 2). The timer may go off just because the path got slower for a moment, with
+// nothing lost: then the ACKs that come in after our retransmission are for the original
+// segments, and acknowledge data we never retransmitted. Sending new data rather than more
+// retransmissions right after the timeout lets us see that.
+#[derive(Debug)]
+enum Frto {
+    // We've retransmitted the oldest segment; `recover` is what SND.NXT was at the time
+    AwaitFirstAck { recover: u32 },
+    // The first ACK advanced the window, and we sent new data in response
+    AwaitSecondAck,
+}
+
+// Explicit Congestion Notification state (RFC 3168). With ECN, routers mark our ECT packets
+// with CE instead of dropping them when they're congested, the receiver echoes that back with
+// ECE on its ACKs, and the sender answers with CWR once it has reacted.
+//
+// A peer that negotiates ECN and then doesn't play along would leave congestion signals
+// unanswered forever, so every inconsistency counts as a violation, and too many of them make
+// us fall back to plain TCP for the rest of the connection.
+#[derive(Debug, Default)]
+struct Ecn {
+    // We've received CE marked data and set ECE on our ACKs until the peer answers with CWR
+    echo_ece: bool,
+    // The peer echoed ECE, so the next new data segment we send carries CWR
+    send_cwr: bool,
+    // Sequence number of the segment that carried our last CWR, until the peer stops echoing.
+    // ECEs that don't acknowledge past it were sent before the peer saw our CWR
+    cwr_seq: Option<u32>,
+    // Whether we've sent a single ECT data segment the network could have marked
+    sent_ect: bool,
+    // Signalling mistakes seen from the peer so far, see ECN_MAX_VIOLATIONS
+    violations: u32,
 }
 
 struct SendSequenceSpace {
@@ -109,9 +324,9 @@ struct SendSequenceSpace {
     // pointer field is in
     up: bool,
     // Sequence number of the segment used for the last window update
-    wl1: usize,
+    wl1: u32,
     // Acknowledgement number used for the last window update
-    wl2: usize,
+    wl2: u32,
     // Initial Send Sequence number -- the first sequence number used when the connection was
     // established
     iss: u32,
@@ -128,135 +343,2739 @@ struct RecvSequenceSpace {
     irs: u32,
 }
 
-
-// Sets default TCP state to 'Listen'
-impl Default for State {
-    fn default() -> Self {
-        State::Listen
-    }
-}
-
-impl State {
-    // Handle incoming TCP packets
-    pub fn on_packet<'a>(
-        &mut self, 
-        ipv4_header: etherparse::Ipv4HeaderSlice<'a>, // Parsed IPv4 Header
-        tcp_header: etherparse::TcpHeaderSlice<'a>, // Parsed TCP Header
-        tcp_payload: &'a [u8], // Reference to payload with lifetime a
-    ) {
-        // Log metadata of packet
-        eprintln!(
-            "{}:{} -> {}:{} {}b of TCP",
-            ipv4_header.source_addr(),
-            tcp_header.source_port(),
-            ipv4_header.destination_addr(),
-            tcp_header.destination_port(),
-            tcp_payload.len()
-        );
-    }
-}
-
-
 impl Connection {
     // Handles incoming TCP packet for establishing a connection
     // If incoming packet is a SYN, it prepares and sends a SYN-ACK packet in response.
-    // Otherwise, the packet is ignored. 
+    // Otherwise, the packet is ignored.
     //
-    // Returns a new `Connection` in the `SynRcvd` state if the incoming packet was a SYN packet
+    // Returns a new `Connection` in the `SynRcvd` state if the incoming packet was a SYN packet.
+    // `iss` is our initial sequence number for it
     pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
+        nic: &mut Nic,
         ipv4_header: etherparse::Ipv4HeaderSlice<'a>,
         tcp_header: etherparse::TcpHeaderSlice<'a>,
         tcp_payload: &'a [u8],
+        iss: u32,
+        config: &TcpConfig,
+        memory_room: Option<usize>,
     ) -> io::Result<Option<Self>> {
-        let mut buf = [0u8; 1500];
         if !tcp_header.syn() {
             // Ignore packets that aren't SYN packets
             return Ok(None);
         }
-        let iss = 0;
-        let wnd = 10;
+        let mut connection = Connection::new(
+            State::SynRcvd,
+            (
+                ipv4_header.destination_addr(),
+                tcp_header.destination_port(),
+            ),
+            (ipv4_header.source_addr(), tcp_header.source_port()),
+            iss,
+            config,
+            memory_room,
+        )?;
+        // The peer's window, from its SYN. Window fields are taken as they are, never shifted:
+        // window scaling (RFC 7323) is only in effect if both SYNs carry the option, and ours
+        // never do. So a Window Scale option from the peer is only recorded (clamped to the 14
+        // that RFC 7323 Sec. 2.3 caps it at, see parse_window_scale), and there's no shifted
+        // window to overflow
+        connection.send.wnd = tcp_header.window_size();
+        // Initialize receive sequence number to the incoming sequence number
+        connection.recv.irs = tcp_header.sequence_number();
+        // The SYN consumes a sequence number, so the first byte of data comes right after it. An
+        // ISN of 2^32 - 1 makes that 0
+        connection.recv.nxt = connection.recv.irs.wrapping_add(1);
+        connection.peer_mss = parse_peer_mss(&tcp_header, config);
+        connection.peer_window_scale = parse_window_scale(&tcp_header);
+        connection.ecn = ecn_setup_requested(&tcp_header).then(Ecn::default);
+        connection.passive = true;
+        if let Some(trace) = connection.trace.as_mut() {
+            trace.record(
+                false,
+                SegmentSummary::of_slice(&tcp_header),
+                tcp_payload.len(),
+            );
+        }
+        // Now that we know the segment size, start the congestion window off from it
+        connection.cc =
+            congestion::from_name(connection.cc.name(), connection.max_segment_payload())?;
+
+        // Send the SYN-ACK packet
+        connection.tcp.syn = true;
+        connection.tcp.ack = true;
+        connection.write(nic, connection.send.nxt, 0)?;
+        Ok(Some(connection))
+    }
+
+    // Opens a connection from `local` to `remote` (active OPEN): sends a SYN and returns the
+    // new `Connection` in the `SynSent` state
+    pub fn connect(
+        nic: &mut Nic,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        config: &TcpConfig,
+        memory_room: Option<usize>,
+    ) -> io::Result<Self> {
+        let iss = initial_sequence_number();
+        let mut connection =
+            Connection::new(State::SynSent, local, remote, iss, config, memory_room)?;
+
+        connection.tcp.syn = true;
+        connection.write(nic, connection.send.nxt, 0)?;
+        Ok(connection)
+    }
+
+    // A connection in `state` between `local` and `remote`, with nothing sent or received yet.
+    // What we learn about the peer (its ISN, window, MSS) is filled in by the caller.
+    // `memory_room` is how much buffer memory is left in the interface's budget, if it has one
+    fn new(
+        state: State,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        iss: u32,
+        config: &TcpConfig,
+        memory_room: Option<usize>,
+    ) -> io::Result<Self> {
+        // The whole receive buffer is free to begin with. Window scaling can't be in effect on
+        // the handshake segments, so a larger buffer still only gets the unscaled maximum. With
+        // the memory budget running low, we offer no more than what's left of it
+        let mut wnd = max_window(config);
+        if let Some(room) = memory_room {
+            wnd = cmp::min(wnd, room);
+        }
+        let wnd = wnd as u16;
         let mut connection = Connection {
-            state: State::SynRcvd,
+            state,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
-                nxt: 1,
-                wnd: wnd,
+                // The SYN we're about to send will consume one sequence number (see `write`)
+                nxt: iss,
+                wnd: 0,
                 up: false,
                 wl1: 0,
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
-                // Initialize receive sequence number to the incoming sequence number
-                irs: tcp_header.sequence_number(),
-                // Expect the next byte after the incoming sequence number
-                nxt: tcp_header.sequence_number() + 1,
-                // Use incoming packet's window size for our receive window
-                wnd: tcph.window_size(),
+                irs: 0,
+                nxt: 0,
+                // The window we advertise to the peer
+                wnd,
                 up: false,
             },
 
-            // Prepare SYN-ACK packet in response to SYN packet
-            tcp: etherparse::TcpHeader::New(
-                tcp_header.destination_port(),
-                tcp_header.source_port(),
-                iss,
-                wnd,
-            ),
+            tcp: etherparse::TcpHeader::new(local.1, remote.1, iss, wnd),
             ip: etherparse::Ipv4Header::new(
-                syn_ack.header_len(),               // payload length
-                64,                                 // Time-to-live
-                etherparse::IpNumber::Tcp as u8,    // Protocol
-                [                                   // Source
-                    ip_header.destination()[0],
-                    ip_header.destination()[1],
-                    ip_header.destination()[2],
-                    ip_header.destination()[3],
-                ],
-                [                                   // Destination
-                    ip_header.source()[0],
-                    ip_header.source()[1],
-                    ip_header.source()[2],
-                    ip_header.source()[3],
-                ],
-            )
+                0,                               // payload length (set when writing)
+                64,                              // Time-to-live
+                etherparse::IpNumber::Tcp as u8, // Protocol
+                local.0.octets(),                // Source
+                remote.0.octets(),               // Destination
+            ),
+
+            ip_ids: IpIds::new(config.ip_id),
+
+            incoming: Arc::new(Mutex::new(RecvBuffer {
+                low_water: config.recv_buffer.low,
+                ignore_psh: config.ignore_psh,
+                ..RecvBuffer::default()
+            })),
+            urgent: VecDeque::new(),
+            held: SeqIntervalSet::new(),
+            held_data: Vec::new(),
+            unacked: Arc::new(Mutex::new(SendBuffer {
+                high_water: config.send_buffer.high,
+                open: memory_room.is_none(),
+                ..SendBuffer::default()
+            })),
+
+            peer_mss: DEFAULT_MSS,
+            peer_window_scale: None,
+            path_mtu: None,
+            maxseg: None,
+            priority: 0,
+
+            trace: (config.trace_segments > 0).then(|| SegmentTrace::new(config.trace_segments)),
+
+            rto_deadline: None,
+            rto: INITIAL_RTO,
+            retransmits: 0,
+            persist_deadline: None,
+            persist_interval: INITIAL_RTO,
+
+            ecn: None,
+
+            cc: congestion::from_name(&config.congestion_control, DEFAULT_MSS)?,
+            frto: None,
+            dup_acks: 0,
+            recover: None,
+
+            ack_pending: false,
+            ack_now: false,
+            unacked_rcv_bytes: 0,
+            ack_deadline: None,
+            paused: false,
+            rd_shut: false,
+            error_pending: false,
+            fin_queued: false,
+            streams: 1,
+            detached: false,
+            time_wait_deadline: None,
+            last_heard: Instant::now(),
+            keepalive_probes_sent: 0,
+            challenge_acks_since: Instant::now(),
+            challenge_acks: 0,
+
+            config: config.clone(),
+
+            passive: false,
+            close_reason: None,
+            close_counted: false,
+            bytes_sent: 0,
+            bytes_retransmitted: 0,
+            bytes_acked: 0,
+            bytes_received: 0,
+            bytes_delivered: 0,
         };
 
-        connection.tcp.acknowledgement_number = c.recv.nxt;
-        connection.tcp.syn = true;
-        connection.tcp.ack = true;
+        // Leave fragmentation to path MTU discovery: routers drop our oversized packets instead
+        connection.ip.dont_fragment = true;
+        Ok(connection)
+    }
+
+    // Builds a segment out of our TCP/IP header templates and sends it. The segment starts at
+    // sequence number `seq` and carries up to `limit` bytes of our queued data from that point on
+    // (limited further by what fits in one packet). Its acknowledgement number is RCV.NXT.
+    // SND.NXT is advanced past everything the segment occupies in the sequence space (including
+    // the SYN/FIN flags, which each consume one sequence number), unless it's a retransmission
+    // of something we've sent before.
+    //
+    // Returns the number of payload bytes written
+    fn write(&mut self, nic: &mut Nic, seq: u32, limit: usize) -> io::Result<usize> {
+        self.write_segments(nic, seq, limit, 1)
+    }
+
+    // Like `write`, for up to `max_segments` segments' worth of data at once. More than one
+    // segment goes to the NIC as a single frame for the kernel to cut up (see
+    // Nic::send_segments), so `max_segments` above 1 is only for a NIC whose
+    // segments_offloaded() says so. The frame counts as one segment here: one trace record, one
+    // IP Identification, one set of flags (which the kernel sorts out for the segments, PSH and
+    // FIN only on the last)
+    fn write_segments(
+        &mut self,
+        nic: &mut Nic,
+        seq: u32,
+        limit: usize,
+        max_segments: usize,
+    ) -> io::Result<usize> {
+        self.tcp.sequence_number = seq;
+        self.tcp.acknowledgment_number = self.recv.nxt;
+        if self.tcp.ack {
+            // This segment acknowledges everything received so far
+            self.ack_pending = false;
+            self.ack_now = false;
+            self.unacked_rcv_bytes = 0;
+            self.ack_deadline = None;
+        }
+        self.tcp.window_size = self.recv.wnd;
+
+        // Whether this segment carries data we haven't sent before, as opposed to being a
+        // retransmission or carrying no data at all
+        let queued_from_seq = self
+            .send_queue_len()
+            .saturating_sub(seq_offset(self.send.una, seq));
+        let new_data = limit > 0 && queued_from_seq > 0 && !wrapping_lt(seq, self.send.nxt);
+        self.set_ecn_marks(seq, new_data);
+
+        // Options ride only on the SYN, where we announce our MSS. Setting them (or clearing
+        // them) updates the data offset, so header_len() below includes the option bytes and the
+        // IP payload length and checksum cover the whole header
+        let options: &[etherparse::TcpOptionElement] = if self.tcp.syn {
+            &[etherparse::TcpOptionElement::MaximumSegmentSize(
+                self.local_mss() as u16,
+            )]
+        } else {
+            &[]
+        };
+        self.tcp
+            .set_options(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        // Find the data starting at `seq` in the send queue, which starts at SND.UNA. The queue is
+        // a ring buffer, so it comes in two parts. It stays locked until they're copied out
+        let mut offset = seq_offset(self.send.una, seq);
+        let queue = self.unacked.lock().unwrap();
+        let (mut head, mut tail) = queue.data.as_slices();
+        if head.len() >= offset {
+            head = &head[offset..];
+        } else {
+            offset -= head.len();
+            head = &[];
+            tail = &tail[cmp::min(offset, tail.len())..];
+        }
+
+        // Only send as much of the payload as fits in the segments, and in one IP packet
+        let mss = self.max_segment_payload();
+        let headers_len = self.ip.header_len() + self.tcp.header_len() as usize;
+        let max_data = cmp::min(
+            cmp::min(limit, head.len() + tail.len()),
+            cmp::min(
+                mss.saturating_mul(max_segments),
+                u16::MAX as usize - headers_len,
+            ),
+        );
+        let segments = max_data > mss;
 
-        connection.ip.set_payload_len(c.tcp.header_len() as usize + 0);
+        // A single segment is built in this buffer on the stack: it only has to be contiguous
+        // for the single nic.send, which copies it if it has to wait (into one of the
+        // interface's pooled buffers, see BufferPool). Several are too big for it, and are built
+        // in a pooled buffer straight away
+        let mut stack_buf = [0u8; 1504];
+        let mut pooled_buf = Vec::new();
+        let buf: &mut [u8] = if segments {
+            pooled_buf = nic.buffers.take();
+            pooled_buf.resize(4 + headers_len + max_data, 0);
+            &mut pooled_buf
+        } else {
+            &mut stack_buf
+        };
 
-        // Calculate and set the checksum for the SYN-ACK packet
-        connection.tcp.checksum = connection.tcp
-            .calc_checksum_ipv4(&connection.ip, &[]) // Empty payload: Empty array
-            .expect("Failed to compute checksum");
+        // Nothing more than fits in the buffer after the headers (and the 4 byte TUN frame
+        // prefix). `size` is the size of the IP packet
+        let size = cmp::min(
+            buf.len() - 4,
+            self.tcp.header_len() as usize + self.ip.header_len() + max_data,
+        );
+        self.ip
+            .set_payload_len(size - self.ip.header_len())
+            .expect("Payload too long for IPv4 packet");
+        self.ip.identification = self.ip_ids.next(self.ip.dont_fragment);
 
-        // Write out TCP and IP headers to a buffer to be sent
+        // Write out the IP header, then the payload, and the TCP header last: the checksum covers
+        // the payload, which we need as one contiguous slice to calculate it
         // Kinda confusing variable shadowing pattern here, is a common Rust idiom:
-        let unwritten: usize = {
-            let mut unwritten = &mut buf[..]; // (type: &mut [u8]) - shadows outer `unwritten`
-            ip.write(&mut unwritten);         // Writes to inner unwritten
-            syn_ack.write(&mut unwritten)     // Writes to inner unwritten
-            unwritten.len()                   // Returns length of inner unwritten, assign to outer
+        let buf_len = buf.len();
+        let mut unwritten = &mut buf[..]; // (type: &mut [u8]) - shadows outer buffer
+                                          // The TUN device expects the same flags + protocol prefix on frames we send it as on the
+                                          // ones it gives us (IPv4 is EtherType 0x0800)
+        unwritten.write_all(&[0, 0, 0x08, 0x00])?;
+        self.ip
+            .write(&mut unwritten) // Writes to inner unwritten
+            .map_err(io::Error::other)?;
+        let ip_header_ends_at = buf_len - unwritten.len();
+
+        // Leave room for the TCP header
+        unwritten = &mut unwritten[self.tcp.header_len() as usize..];
+        let tcp_header_ends_at = buf_len - unwritten.len();
+
+        let payload_bytes = {
+            let mut written = 0;
+            let mut limit = size - self.ip.header_len() - self.tcp.header_len() as usize;
+
+            let head_len = cmp::min(limit, head.len());
+            written += unwritten.write(&head[..head_len])?;
+            limit -= written;
+
+            let tail_len = cmp::min(limit, tail.len());
+            written += unwritten.write(&tail[..tail_len])?;
+            written
         };
+        let payload_ends_at = buf_len - unwritten.len();
+        let queued = queue.data.len();
+        drop(queue);
 
-        // Send the SYN-ACK packet
-        nic.send(&buf[..unwritten])?;
-        Ok(Some(connection))
+        // The last of the queued data goes out with PSH, so the peer hands it to its application
+        // rather than waiting for more to come with it. Writes have no way to ask for a push,
+        // and then that's where it has to be (RFC 1122 Sec. 4.2.2.2)
+        self.tcp.psh =
+            payload_bytes > 0 && seq_offset(self.send.una, seq) + payload_bytes == queued;
+
+        // Calculate and set the checksum for the segment, then fill in the TCP header. The
+        // kernel works out each of the segments' checksums itself, from that of the
+        // pseudo-header
+        self.tcp.checksum = if segments {
+            pseudo_header_checksum(&self.ip, payload_ends_at - ip_header_ends_at)
+        } else {
+            self.tcp
+                .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payload_ends_at])
+                .expect("Failed to compute checksum")
+        };
+        let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
+        self.tcp.write(&mut tcp_header_buf)?;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(true, SegmentSummary::of_header(&self.tcp), payload_bytes);
+        }
+
+        // Whatever part of the payload comes before SND.NXT has gone out before
+        self.bytes_sent += payload_bytes as u64;
+        if wrapping_lt(seq, self.send.nxt) {
+            self.bytes_retransmitted +=
+                cmp::min(payload_bytes, seq_offset(seq, self.send.nxt)) as u64;
+        }
+
+        // Work out where this segment ends in the sequence space
+        let mut next_seq = seq.wrapping_add(payload_bytes as u32);
+        if self.tcp.syn {
+            next_seq = next_seq.wrapping_add(1);
+            self.tcp.syn = false;
+        }
+        if self.tcp.fin {
+            next_seq = next_seq.wrapping_add(1);
+            self.tcp.fin = false;
+        }
+        if wrapping_lt(self.send.nxt, next_seq) {
+            self.send.nxt = next_seq;
+        }
+
+        // Anything that occupies sequence space may need retransmitting, so start the timer if
+        // it isn't running already (RFC 6298 Sec. 5.1). A running timer is left alone: it's
+        // timing the oldest outstanding segment, not this one
+        if next_seq != seq && self.rto_deadline.is_none() {
+            self.rto_deadline = Some(Instant::now() + self.rto);
+        }
+
+        if segments {
+            pooled_buf.truncate(payload_ends_at);
+            let segmentation = Segmentation {
+                ip_header_len: self.ip.header_len() as u16,
+                header_len: headers_len as u16,
+                segment_size: mss as u16,
+            };
+            nic.send_segments(pooled_buf, segmentation)?;
+        } else {
+            nic.send(&stack_buf[..payload_ends_at])?;
+        }
+        Ok(payload_bytes)
     }
 
-    // Function to handle incoming packets once a connection is established
-    pub fn on_packet<'a>(
+    // Fills in the ECN bits of the segment we're about to send (RFC 3168 Sec. 6.1). Only new data
+    // goes out as ECT: pure ACKs, the SYN-ACK and retransmissions must not be ECN-capable.
+    fn set_ecn_marks(&mut self, seq: u32, new_data: bool) {
+        self.ip.explicit_congestion_notification = 0;
+        self.tcp.cwr = false;
+        self.tcp.ece = false;
+        let Some(ecn) = self.ecn.as_mut() else {
+            return;
+        };
+
+        if self.tcp.syn {
+            // ECE (without CWR) on the SYN-ACK tells the peer we agree to use ECN
+            self.tcp.ece = true;
+            return;
+        }
+        self.tcp.ece = ecn.echo_ece;
+        if new_data {
+            self.ip.explicit_congestion_notification = ECN_ECT0;
+            ecn.sent_ect = true;
+            if ecn.send_cwr {
+                self.tcp.cwr = true;
+                ecn.send_cwr = false;
+                ecn.cwr_seq = Some(seq);
+            }
+        }
+    }
+
+    // Processes the ECN signals of an incoming segment, and gives up on ECN if the peer keeps
+    // getting them wrong
+    fn on_ecn_signals(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        ipv4_header: etherparse::Ipv4HeaderSlice<'a>,
-        tcp_header: etherparse::TcpHeaderSlice<'a>,
-        tcp_payload: &'a [u8], 
-    ) -> io::Result<()> {
-        // Process incoming packet based on its flags and current connection state
+        ipv4_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        has_data: bool,
+    ) {
+        let in_flight = self.data_in_flight();
+        let mss = self.max_segment_payload();
+        let Some(ecn) = self.ecn.as_mut() else {
+            return;
+        };
+
+        // Our side as the receiver: echo congestion the network reported on the peer's data
+        // until the peer tells us it has reacted to it
+        if tcp_header.cwr() {
+            ecn.echo_ece = false;
+        }
+        if ipv4_header.ecn() == ECN_CE {
+            ecn.echo_ece = true;
+        } else if ecn.echo_ece && has_data && !tcp_header.cwr() {
+            // The peer should set CWR on the first new data it sends after seeing our ECE, so
+            // data that keeps coming without it means our echoes are being ignored
+            ecn.violations += 1;
+        }
+
+        // Our side as the sender: an ECE means the network marked data we sent. We shrink the
+        // congestion window as if a segment were lost, and answer with CWR so the peer stops
+        // echoing
+        if tcp_header.ack() {
+            let ack_number = tcp_header.acknowledgment_number();
+            let cwr_seen = ecn.cwr_seq.map(|cwr_seq| wrapping_lt(cwr_seq, ack_number));
+            match (tcp_header.ece(), cwr_seen) {
+                (true, _) if !ecn.sent_ect => {
+                    // Nothing we sent could have been marked, so there's nothing to echo
+                    ecn.violations += 1;
+                }
+                (true, None) => {
+                    // Only react once per round of congestion: several ACKs may echo it
+                    // before our CWR goes out
+                    if !ecn.send_cwr {
+                        self.cc.on_congestion(in_flight, mss);
+                    }
+                    ecn.send_cwr = true;
+                }
+                (true, Some(true)) => {
+                    // The peer has seen our CWR and should have stopped echoing
+                    ecn.violations += 1;
+                    ecn.cwr_seq = None;
+                    ecn.send_cwr = true;
+                }
+                // Still in flight from before the peer saw our CWR
+                (true, Some(false)) => {}
+                // The peer has stopped echoing: this round of congestion is over
+                (false, Some(true)) => ecn.cwr_seq = None,
+                (false, _) => {}
+            }
+        }
+
+        if ecn.violations >= ECN_MAX_VIOLATIONS {
+            eprintln!(
+                "{}:{} keeps getting ECN signalling wrong, falling back to non-ECN",
+                ipv4_header.source_addr(),
+                tcp_header.source_port(),
+            );
+            self.ecn = None;
+        }
+    }
+
+    // Sends the ACK owed for the segments received since the last one, if it's due. Called by
+    // the packet loop after every batch of incoming segments.
+    //
+    // ACKs for in-order data are delayed (RFC 1122 Sec. 4.2.3.2): we only acknowledge once
+    // there's enough of it, by default every second full-sized segment (RFC 5681 Sec. 4.2), or
+    // when the delayed ACK timer goes off. Anything else is acknowledged right away
+    pub fn flush_ack(&mut self, nic: &mut Nic) -> io::Result<()> {
+        if !self.ack_pending || self.is_closed() {
+            return Ok(());
+        }
+        if self.ack_now || self.unacked_rcv_bytes >= self.delayed_ack_threshold() {
+            self.write(nic, self.send.nxt, 0)?;
+        } else if self.ack_deadline.is_none() {
+            self.ack_deadline = Some(Instant::now() + DELAYED_ACK_TIMEOUT);
+        }
+        Ok(())
+    }
+
+    // How much in-order data we let pile up before acknowledging it: two full-sized segments,
+    // unless configured otherwise. Full-sized as negotiated on the handshake, the smaller of the
+    // two MSSs: the one we announce bounds what the peer may send us, and the one it announces
+    // is what its own link carries, which it won't send us more than either. The path and
+    // set_maxseg only limit what we send, so they don't come into it
+    fn delayed_ack_threshold(&self) -> usize {
+        let mss = cmp::min(self.peer_mss, self.local_mss());
+        self.config.delayed_ack_bytes.unwrap_or(2 * mss)
+    }
+
+    // Called periodically by the packet loop, independently of incoming segments.
+    // Retransmits if the retransmission timer has gone off, then sends whatever queued data the
+    // peer's window has room for, followed by our FIN once the application is done sending.
+    // `memory_room` is how much buffer memory is left in the interface's budget, if it has one
+    pub fn on_tick(&mut self, nic: &mut Nic, memory_room: Option<usize>) -> io::Result<()> {
+        if let Some(deadline) = self.time_wait_deadline {
+            if Instant::now() >= deadline {
+                self.close(CloseReason::Graceful);
+                return Ok(());
+            }
+        }
+        if let Some(deadline) = self.ack_deadline {
+            if Instant::now() >= deadline && self.ack_pending {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+        }
+        if let Some(deadline) = self.rto_deadline {
+            if Instant::now() >= deadline {
+                self.retransmit(nic)?;
+            }
+        }
+        self.keepalive(nic)?;
+
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            self.update_window(nic, memory_room)?;
+        }
+        if self.paused {
+            // Nothing new goes out, our FIN included, until we're resumed. Retransmissions of
+            // what's already in flight still do (above), that's not new
+            return Ok(());
+        }
+
+        // The peer's FIN only says it won't send anymore, not that it won't receive: in
+        // CLOSE-WAIT our send queue keeps going out (and the application may keep adding to
+        // it) just like in ESTABLISHED, and our own FIN still waits for the last of it
+        if !matches!(self.state, State::Estab | State::CloseWait) {
+            return Ok(());
+        }
+        if !self.send_queue_intact() {
+            return self.abort(nic, CloseReason::Reset);
+        }
+
+        self.fill_pipe(nic)?;
+        self.persist(nic)?;
+
+        let all_sent = seq_offset(self.send.una, self.send.nxt) == self.send_queue_len();
+        if self.fin_queued && all_sent {
+            self.send_fin(nic)?;
+        }
+        Ok(())
+    }
+
+    // Sends as much queued data as the windows allow: the smaller of the peer's receive window
+    // and our congestion window, less what's already in flight. The data goes out in segments
+    // of up to the MSS, the last one (or the only one, if the window is smaller than a segment)
+    // carrying whatever is left of the allowance. Returns how many bytes were sent.
+    //
+    // Normally that's one write to the NIC per segment. With segmentation offload (see
+    // TcpConfig::segmentation_offload) it's one per IP packet's worth of segments, which the
+    // kernel cuts up, if the NIC is up for that; if it isn't, it's one per segment after all
+    fn fill_pipe(&mut self, nic: &mut Nic) -> io::Result<usize> {
+        let in_flight = seq_offset(self.send.una, self.send.nxt);
+        let unsent = self.send_queue_len().saturating_sub(in_flight);
+        // Three limits, and we take the smallest: the peer's window, the congestion window, and
+        // what's actually queued. However large a window the peer advertises (we don't
+        // negotiate window scaling, so at most 64K), it never lets us send more than congestion
+        // control allows, or invent data past the end of the send queue
+        let window = cmp::min(self.send.wnd as usize, self.cc.window());
+        let mut allowed = cmp::min(unsent, window.saturating_sub(in_flight));
+        let max_segments = if self.config.segmentation_offload && nic.segments_offloaded() {
+            usize::MAX
+        } else {
+            1
+        };
+
+        let mut sent = 0;
+        while allowed > 0 {
+            // `write_segments` advances SND.NXT past whatever it sent
+            let nbytes = self.write_segments(nic, self.send.nxt, allowed, max_segments)?;
+            if nbytes == 0 {
+                break;
+            }
+            sent += nbytes;
+            allowed -= nbytes;
+        }
+        Ok(sent)
+    }
+
+    // Runs the persist timer (RFC 1122 Sec. 4.2.2.17). Once the peer's window is closed, only a
+    // window update from it gets us sending again. That's a pure ACK, and nothing retransmits
+    // it if it's lost, so both sides would wait on each other forever. Instead, while the window
+    // stays closed and we have data for it, we probe it with one byte beyond the window every so
+    // often, which the peer answers with an ACK showing its current window. Unlike
+    // retransmissions, the probes go on for as long as the peer keeps answering.
+    fn persist(&mut self, nic: &mut Nic) -> io::Result<()> {
+        if self.send.wnd != 0 {
+            self.persist_deadline = None;
+            return Ok(());
+        }
+        let in_flight = seq_offset(self.send.una, self.send.nxt);
+        match self.persist_deadline {
+            None => {
+                let unsent = self.send_queue_len().saturating_sub(in_flight);
+                if in_flight == 0 && unsent > 0 {
+                    self.persist_interval = self.rto;
+                    self.persist_deadline = Some(Instant::now() + self.persist_interval);
+                }
+            }
+            Some(deadline) if Instant::now() >= deadline => {
+                // Either a new byte, or the previous probe's again if the peer didn't take it
+                if in_flight == 0 {
+                    self.write(nic, self.send.nxt, 1)?;
+                } else {
+                    self.write(nic, self.send.una, 1)?;
+                }
+                // The persist timer looks after the probe byte, not the retransmission timer
+                self.rto_deadline = None;
+                self.persist_interval = cmp::min(self.persist_interval * 2, MAX_RTO);
+                self.persist_deadline = Some(Instant::now() + self.persist_interval);
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    // Whether another challenge ACK may go out, counting it if so: no more than
+    // TcpConfig::challenge_ack_limit in any one second (RFC 5961 Sec. 7)
+    fn challenge_ack_allowed(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.challenge_acks_since) >= Duration::from_secs(1) {
+            self.challenge_acks_since = now;
+            self.challenge_acks = 0;
+        }
+        if self.challenge_acks >= self.config.challenge_ack_limit {
+            return false;
+        }
+        self.challenge_acks += 1;
+        true
+    }
+
+    // When the next keep-alive probe is due (or the connection is given up on, if that many
+    // have gone unanswered already), if keep-alives are on and the connection is idle
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let idle = self.config.keepalive_idle?;
+        if !matches!(
+            self.state,
+            State::Estab | State::CloseWait | State::FinWait2
+        ) || self.send.una != self.send.nxt
+        {
+            return None;
+        }
+        Some(self.last_heard + idle + self.config.keepalive_interval * self.keepalive_probes_sent)
+    }
+
+    // Probes an idle connection for whether the peer is still there, if keep-alives are on, and
+    // gives up on it once enough probes in a row have gone unanswered. Only a connection with
+    // nothing in flight counts as idle: otherwise the retransmission timer is already finding
+    // out whether the peer is alive.
+    fn keepalive(&mut self, nic: &mut Nic) -> io::Result<()> {
+        match self.keepalive_deadline() {
+            Some(due) if Instant::now() >= due => {}
+            _ => return Ok(()),
+        }
+        if self.keepalive_probes_sent >= self.config.keepalive_probes {
+            eprintln!(
+                "No answer to {} keep-alive probes, giving up on the connection",
+                self.keepalive_probes_sent
+            );
+            return self.abort(nic, CloseReason::Timeout);
+        }
+        self.keepalive_probes_sent += 1;
+
+        // A segment from before what the peer has already received, which it can only answer
+        // with an ACK (see TcpConfig::keepalive_probe_bytes). It's built apart from `write`,
+        // which only sends from SND.UNA on, and whatever byte it carries never reaches the
+        // peer's stream
+        let mut ip = self.ip.clone();
+        ip.explicit_congestion_notification = 0;
+        ip.identification = self.ip_ids.next(ip.dont_fragment);
+        let mut tcp = etherparse::TcpHeader::new(
+            self.tcp.source_port,
+            self.tcp.destination_port,
+            self.send.nxt.wrapping_sub(1),
+            self.recv.wnd,
+        );
+        tcp.ack = true;
+        tcp.acknowledgment_number = self.recv.nxt;
+        let payload: &[u8] = if self.config.keepalive_probe_bytes > 0 {
+            &[0]
+        } else {
+            &[]
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(true, SegmentSummary::of_header(&tcp), payload.len());
+        }
+        send_segment(nic, &mut ip, &mut tcp, payload)
+    }
+
+    // Opens the receive window back up as the application reads, and tells the peer about it.
+    // To avoid silly window syndrome (RFC 1122 Sec. 4.2.3.3), the window only grows once it can
+    // grow by a full segment or half the buffer, whichever is less: a peer shown a window that
+    // opens a few bytes at a time would otherwise fill it with tiny segments.
+    //
+    // An open window is memory the peer may fill at any time, so under a memory budget it never
+    // grows by more than `memory_room`, what's left of the budget.
+    fn update_window(&mut self, nic: &mut Nic, memory_room: Option<usize>) -> io::Result<()> {
+        if self.paused {
+            // Stop the peer with a zero window, and keep it there (answering its window probes
+            // like any other out-of-window segment) until we're resumed
+            if self.recv.wnd != 0 {
+                self.recv.wnd = 0;
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(());
+        }
+        let buffered = self.recv_buffered() + self.urgent.len();
+        let max_window = max_window(&self.config);
+        let mut free = cmp::min(
+            self.config.recv_buffer.high.saturating_sub(buffered),
+            max_window,
+        );
+        if let Some(room) = memory_room {
+            free = cmp::min(free, self.recv.wnd as usize + room);
+        }
+        let mss = self.local_mss();
+        // With the window capped below the buffer size, the cap is what it can grow into
+        let threshold = cmp::min(max_window / 2, mss);
+        if free >= self.recv.wnd as usize + threshold {
+            self.recv.wnd = free as u16;
+            self.write(nic, self.send.nxt, 0)?;
+        }
+        Ok(())
+    }
+
+    // Sends our FIN right after the last byte of data, closing our side of the connection.
+    // The FIN takes up a sequence number of its own, so `write` moves SND.NXT one past the last
+    // data byte and arms the retransmission timer for it. From then on `fin_in_flight` says
+    // it's outstanding: `retransmit` sends it again (on its own or with the tail of the data)
+    // until an ACK reaching SND.NXT moves us out of FIN-WAIT-1 or LAST-ACK
+    fn send_fin(&mut self, nic: &mut Nic) -> io::Result<()> {
+        self.tcp.fin = true;
+        self.write(nic, self.send.nxt, 0)?;
+        self.state = match self.state {
+            State::CloseWait => State::LastAck,
+            _ => State::FinWait1,
+        };
+        Ok(())
+    }
+
+    // Whether our FIN has been sent but not acknowledged yet
+    fn fin_in_flight(&self) -> bool {
+        matches!(
+            self.state,
+            State::FinWait1 | State::Closing | State::LastAck
+        )
+    }
+
+    // How much of what's in flight is data, as opposed to our SYN or FIN
+    fn data_in_flight(&self) -> usize {
+        let in_flight = seq_offset(self.send.una, self.send.nxt);
+        in_flight.saturating_sub(self.syn_in_flight() as usize + self.fin_in_flight() as usize)
+    }
+
+    // Whether our SYN has been sent but not acknowledged yet: SND.UNA is still at the ISS, and
+    // SND.NXT one past it. Whatever the application has written meanwhile waits in the queue
+    // for the handshake to complete
+    fn syn_in_flight(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRcvd) && self.send.una == self.send.iss
+    }
+
+    // Counts a duplicate ACK, and on the third in a row retransmits the oldest segment right
+    // away and halves the congestion window, as for an ECN echo: the segments after the lost
+    // one are getting through, so the path is congested rather than down, and there's no need
+    // to wait for the timer and start over from one segment. While timeout recovery (or F-RTO)
+    // is under way, duplicate ACKs are mostly answers to our own retransmissions, so they're
+    // only counted
+    fn on_duplicate_ack(&mut self, nic: &mut Nic) -> io::Result<()> {
+        self.dup_acks += 1;
+        if self.dup_acks != DUP_ACK_THRESHOLD
+            || self.recover.is_some()
+            || self.retransmits > 0
+            || self.frto.is_some()
+        {
+            return Ok(());
+        }
+        let data_in_flight = self.data_in_flight();
+        let mss = self.max_segment_payload();
+        eprintln!(
+            "{} duplicate ACKs for {}, retransmitting it",
+            self.dup_acks, self.send.una
+        );
+        self.cc.on_congestion(data_in_flight, mss);
+        self.recover = Some(self.send.nxt);
+        if self.fin_in_flight() && data_in_flight <= mss {
+            self.tcp.fin = true;
+        }
+        self.write(nic, self.send.una, data_in_flight)?;
+        self.tcp.fin = false;
+        Ok(())
+    }
+
+    // Called when the retransmission timer goes off: resends the oldest unacknowledged segment
+    // and backs the timer off
+    fn retransmit(&mut self, nic: &mut Nic) -> io::Result<()> {
+        // An unanswered SYN is given up on sooner than a connection that's been working
+        let max_retransmits = match self.state {
+            State::SynSent => self.config.max_syn_retransmits,
+            _ => self.config.max_retransmits,
+        };
+        if self.retransmits >= max_retransmits {
+            eprintln!(
+                "No answer after {} retransmissions, giving up on the connection",
+                self.retransmits
+            );
+            return self.abort(nic, CloseReason::Timeout);
+        }
+        self.retransmits += 1;
+
+        match self.state {
+            State::SynSent => {
+                // Our SYN went missing (or the peer's SYN-ACK did). It goes out again just as it
+                // did the first time, with the same ISS and options, so a SYN-ACK for either
+                // copy completes the handshake
+                self.tcp.syn = true;
+                self.write(nic, self.send.una, 0)?;
+            }
+            State::SynRcvd => {
+                // Our SYN-ACK went missing (or the peer's ACK of it did). Some middleboxes drop
+                // ECN-setup SYN-ACKs, so the retransmission goes without ECN, or the handshake
+                // might never get through (RFC 3168 Sec. 6.1.1.1)
+                if self.ecn.take().is_some() {
+                    eprintln!("SYN-ACK timed out, retrying it without ECN");
+                }
+                self.tcp.syn = true;
+                self.tcp.ack = true;
+                self.write(nic, self.send.una, 0)?;
+            }
+            State::Estab | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck => {
+                if !self.send_queue_intact() {
+                    return self.abort(nic, CloseReason::Reset);
+                }
+                let data_in_flight = self.data_in_flight();
+                self.detect_blackhole(data_in_flight);
+                // A timeout is the strongest sign of congestion we get
+                let mss = self.max_segment_payload();
+                self.cc.on_timeout(data_in_flight, mss);
+                // Only the first timeout for a segment can be checked for being spurious; a
+                // timeout while we're at it means it wasn't
+                self.frto =
+                    (self.config.frto && self.retransmits == 1).then_some(Frto::AwaitFirstAck {
+                        recover: self.send.nxt,
+                    });
+                // The retransmission starts at SND.UNA, wherever partial ACKs have moved it to.
+                // The send queue is just bytes, with no memory of how they were cut into
+                // segments the first time, so `write` cuts a fresh one from there at the MSS as
+                // it is now (which TcpStream::set_maxseg may have lowered since).
+                //
+                // Our FIN goes along if the segment reaches all the way up to it
+                if self.fin_in_flight() && data_in_flight <= self.max_segment_payload() {
+                    self.tcp.fin = true;
+                }
+                self.write(nic, self.send.una, data_in_flight)?;
+                self.tcp.fin = false;
+            }
+            _ => {
+                self.rto_deadline = None;
+                return Ok(());
+            }
+        }
+
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+        self.rto_deadline = Some(Instant::now() + self.rto);
+        Ok(())
+    }
+
+    // The peer's window opened back up, usually with a pure ACK (a window update). Stops
+    // probing it and sends what's been waiting right away, rather than on the next tick
+    fn on_window_reopened(&mut self, nic: &mut Nic) -> io::Result<()> {
+        if self.persist_deadline.take().is_some() {
+            // A probe byte the peer didn't take is all that can be in flight. It goes out again
+            // at the start of the first segment, rather than waiting for a retransmission
+            self.send.nxt = self.send.una;
+        }
+        if !self.paused && matches!(self.state, State::Estab | State::CloseWait) {
+            self.fill_pipe(nic)?;
+        }
+        Ok(())
+    }
+
+    // Takes F-RTO a step further on an ACK after a retransmission timeout (RFC 5682 Sec. 2,
+    // steps 2 and 3). `advanced` says if the ACK acknowledged new data
+    fn frto_on_ack(&mut self, nic: &mut Nic, advanced: bool, ack_number: u32) -> io::Result<()> {
+        match self.frto.take() {
+            Some(Frto::AwaitFirstAck { recover }) => {
+                // A duplicate ACK says the peer is missing data, and one covering everything up
+                // to `recover` could be for our retransmission as well as for the originals:
+                // either way it's an ordinary timeout, and we carry on as after any other
+                let unsent = self.send_queue_len().saturating_sub(self.data_in_flight());
+                if !advanced || !wrapping_lt(ack_number, recover) || unsent == 0 {
+                    return Ok(());
+                }
+                // Send (up to) two new segments instead of retransmitting: if the next ACK
+                // advances the window again, it's for data that was never lost
+                let mss = self.max_segment_payload();
+                for _ in 0..2 {
+                    let in_flight = seq_offset(self.send.una, self.send.nxt);
+                    let unsent = self.send_queue_len().saturating_sub(in_flight);
+                    let window_left = (self.send.wnd as usize).saturating_sub(in_flight);
+                    let allowed = cmp::min(cmp::min(unsent, window_left), mss);
+                    if allowed == 0 || self.write(nic, self.send.nxt, allowed)? == 0 {
+                        break;
+                    }
+                }
+                self.frto = Some(Frto::AwaitSecondAck);
+            }
+            Some(Frto::AwaitSecondAck) if advanced => {
+                eprintln!("Retransmission timeout was spurious, restoring the congestion window");
+                self.cc.undo_timeout();
+            }
+            // The new data got a duplicate ACK: something was lost after all
+            Some(Frto::AwaitSecondAck) | None => {}
+        }
+        Ok(())
+    }
+
+    // Checks that the send queue still holds every byte from SND.UNA to SND.NXT, which it must
+    // until they're acknowledged: that's what retransmissions are made from. Writes block on a
+    // full queue rather than ever dropping unacknowledged data, so this failing is a bug in our
+    // sequence space bookkeeping. Debug builds stop right there; release builds get false and
+    // should abort the connection rather than send the peer garbage.
+    fn send_queue_intact(&self) -> bool {
+        let in_flight = self.data_in_flight();
+        let intact = in_flight <= self.send_queue_len();
+        debug_assert!(
+            intact,
+            "{} bytes in flight but only {} in the send queue",
+            in_flight,
+            self.send_queue_len()
+        );
+        if !intact {
+            eprintln!(
+                "Send queue invariant violated ({} bytes in flight, {} queued), aborting connection",
+                in_flight,
+                self.send_queue_len()
+            );
+        }
+        intact
+    }
+
+    // Checks that the TCB is consistent with itself, as it must be after every segment and every
+    // tick, whatever the peer sent: a failure is a bug in our own bookkeeping (usually the
+    // sequence number math), not the peer misbehaving, so it panics right there. Only debug
+    // builds check; release builds carry on, apart from what send_queue_intact catches.
+    pub fn check_invariants(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let (una, nxt) = (self.send.una, self.send.nxt);
+        assert!(
+            !wrapping_lt(nxt, una),
+            "SND.NXT {} is behind SND.UNA {}",
+            nxt,
+            una
+        );
+
+        // Everything in flight is queued data, apart from a SYN or FIN, which take up a
+        // sequence number each but no room in the queue
+        let in_flight = seq_offset(una, nxt);
+        assert!(
+            in_flight <= self.send_queue_len() + 2,
+            "{} in flight but only {} bytes queued",
+            in_flight,
+            self.send_queue_len()
+        );
+        // (send_queue_intact only knows about our FIN, so it's left out for the handshake,
+        // when our SYN may be what's in flight, and for CLOSED, which may be a handshake's end)
+        if !matches!(self.state, State::SynSent | State::SynRcvd | State::Closed) {
+            assert!(self.send_queue_intact());
+        }
+        let incoming = self.incoming.lock().unwrap();
+        assert!(
+            incoming.pushed <= incoming.data.len(),
+            "{} bytes pushed but only {} buffered",
+            incoming.pushed,
+            incoming.data.len()
+        );
+        drop(incoming);
+
+        match self.state {
+            State::Listen => panic!("a connection in LISTEN"),
+            // Only the SYN (or SYN-ACK) can be out before the handshake is done. A passive
+            // connection is handed out in SYN-RECEIVED and may have data queued already, but
+            // connect doesn't return before the SYN-ACK is in
+            State::SynSent => {
+                assert!(
+                    in_flight <= 1,
+                    "{} in flight during the handshake",
+                    in_flight
+                );
+                assert!(
+                    self.send_queue_len() == 0,
+                    "data queued before the connection is open"
+                );
+            }
+            State::SynRcvd => {
+                assert!(
+                    in_flight <= 1,
+                    "{} in flight during the handshake",
+                    in_flight
+                );
+            }
+            // Our FIN went out after the last of our data, and it's all been acknowledged
+            State::FinWait2 | State::TimeWait => {
+                assert_eq!(
+                    una, nxt,
+                    "unacknowledged data after our FIN was acknowledged"
+                );
+                assert!(
+                    self.send_queue_len() == 0,
+                    "data queued after our FIN was acknowledged"
+                );
+            }
+            _ => {}
+        }
+        // Once we've had the peer's SYN, RCV.NXT is past it
+        if !matches!(self.state, State::SynSent | State::Closed) {
+            let after_syn = self.recv.irs.wrapping_add(1);
+            assert!(
+                !wrapping_lt(self.recv.nxt, after_syn),
+                "RCV.NXT {} is before the peer's first byte {}",
+                self.recv.nxt,
+                after_syn
+            );
+        }
+    }
+
+    // Abandons the connection: tells the peer with a RST (in case it's still listening) and moves
+    // to CLOSED, after which the application's reads and writes fail
+    fn abort(&mut self, nic: &mut Nic, reason: CloseReason) -> io::Result<()> {
+        self.tcp.rst = true;
+        self.write(nic, self.send.nxt, 0)?;
+        self.tcp.rst = false;
+        self.close(reason);
         Ok(())
     }
+
+    // Moves to CLOSED for the given reason, dropping everything still to be (re)sent
+    fn close(&mut self, reason: CloseReason) {
+        self.state = State::Closed;
+        self.error_pending = reason != CloseReason::Graceful;
+        self.incoming.lock().unwrap().failed = self.error_pending;
+        self.unacked.lock().unwrap().open = false;
+        self.rto_deadline = None;
+        self.time_wait_deadline = None;
+        self.close_reason = Some(reason);
+    }
+
+    // If `tcp_header` is a SYN opening a new incarnation of this connection while it's in
+    // TIME-WAIT, the ISS the new one should use (RFC 1122 Sec. 4.2.2.13). Strictly, TIME-WAIT
+    // lasts its full 2 MSL, but a SYN numbered beyond everything the old connection's peer ever
+    // sent can't be one of its old duplicates. Giving the new connection an ISS past everything
+    // we sent on the old one keeps the old one's stray segments out of it in the other
+    // direction too. Only a connection the application is done with is given up like this
+    pub fn reincarnation_iss(&self, tcp_header: &etherparse::TcpHeaderSlice) -> Option<u32> {
+        let new_syn = tcp_header.syn() && !tcp_header.ack() && !tcp_header.rst();
+        // RCV.NXT is one past the old connection's FIN, its last sequence number
+        let beyond_old = wrapping_lt(self.recv.nxt.wrapping_sub(1), tcp_header.sequence_number());
+        let allowed = self.config.time_wait_syn == TimeWaitSyn::Reincarnate;
+        (matches!(self.state, State::TimeWait) && self.detached && new_syn && beyond_old && allowed)
+            .then(|| self.send.nxt.wrapping_add(u16::MAX as u32 + 1))
+    }
+
+    // Cuts TIME-WAIT short, for a new incarnation of the connection to take its place
+    pub fn end_time_wait(&mut self) {
+        self.close(CloseReason::Graceful);
+    }
+
+    // Enters TIME-WAIT, or restarts it: both sides have closed, but the peer may not have got
+    // the ACK of its FIN, and old duplicates of our segments may still be out there
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.rto_deadline = None;
+        self.time_wait_deadline = Some(Instant::now() + 2 * self.config.msl);
+    }
+
+    // Why the connection closed, if it just did. Returns it only once, so every closed
+    // connection is counted exactly once
+    pub fn take_close_reason(&mut self) -> Option<CloseReason> {
+        if self.close_counted {
+            return None;
+        }
+        self.close_counted = self.close_reason.is_some();
+        self.close_reason
+    }
+
+    // The application is done sending (CLOSE in RFC 793): our FIN follows the queued data
+    pub fn shutdown_write(&mut self) {
+        self.fin_queued = true;
+        self.close_writes();
+    }
+
+    // From now on writes go through the interface's lock, and so find out from the connection
+    // whether (and why) they can't be queued, see SendBuffer::open
+    pub fn close_writes(&mut self) {
+        self.unacked.lock().unwrap().open = false;
+    }
+
+    // Whether the application has shut down its sending side
+    pub fn is_snd_closed(&self) -> bool {
+        self.fin_queued
+    }
+
+    // The application is done reading. Nothing goes out on the wire for this: whatever is still
+    // buffered is dropped, and data arriving from now on is handled as config.read_shutdown says
+    pub fn shutdown_read(&mut self) {
+        self.rd_shut = true;
+        let mut incoming = self.incoming.lock().unwrap();
+        incoming.data.clear();
+        incoming.pushed = 0;
+        drop(incoming);
+        self.urgent.clear();
+        self.held = SeqIntervalSet::new();
+        self.held_data.clear();
+    }
+
+    // Whether the application has shut down its reading side
+    pub fn is_rd_shut(&self) -> bool {
+        self.rd_shut
+    }
+
+    // Another TcpStream refers to the connection now
+    pub fn attach(&mut self) {
+        self.streams += 1;
+    }
+
+    // A TcpStream for the connection is gone. Once the last one is, close the connection, and
+    // let it go once it's CLOSED. With no stream left nothing will ever read again, so the read
+    // side is shut down too: otherwise data still arriving would pile up unread until the
+    // window closed, and a peer still sending would be stuck forever, waiting on a window
+    // that never reopens and never getting to our FIN
+    pub fn detach(&mut self) {
+        self.streams -= 1;
+        if self.streams > 0 {
+            return;
+        }
+        self.shutdown_write();
+        self.shutdown_read();
+        self.detached = true;
+    }
+
+    // Whether the connection can be forgotten about
+    pub fn is_finished(&self) -> bool {
+        self.detached && self.is_closed()
+    }
+
+    // Whether the connection is gone for good (e.g. it was aborted)
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    // Whether the connection failed since the application last heard about it, clearing the
+    // flag: the application is to be told about each failure once
+    pub fn take_error(&mut self) -> bool {
+        std::mem::take(&mut self.error_pending)
+    }
+
+    // Why the connection closed, if it has
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    // Whether the connection is open in both directions (ESTABLISHED)
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, State::Estab)
+    }
+
+    // Whether both FINs have been exchanged and acknowledged, and we're only lingering in
+    // TIME-WAIT to catch stray segments
+    pub fn is_time_wait(&self) -> bool {
+        matches!(self.state, State::TimeWait)
+    }
+
+    // Whether we're still waiting for the peer to answer our SYN
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent)
+    }
+
+    // Whether the connection came in through a listener
+    pub fn is_passive(&self) -> bool {
+        self.passive
+    }
+
+    // The most payload one segment may carry: no more than the peer's MSS, and no more than fits
+    // in a single packet on the path. With DF set, anything bigger than the path MTU would be
+    // dropped on the way rather than fragmented. Without a path MTU estimate we assume the path
+    // can carry what our own link can. A maxseg set by the application lowers this further.
+    fn max_segment_payload(&self) -> usize {
+        let mtu = self.path_mtu.unwrap_or(LINK_MTU);
+        // Data segments carry no TCP options
+        let headers = self.ip.header_len() + etherparse::TCP_MINIMUM_HEADER_SIZE;
+        let negotiated = cmp::min(self.peer_mss, mtu.saturating_sub(headers));
+        match self.maxseg {
+            Some(maxseg) => cmp::min(negotiated, maxseg),
+            None => negotiated,
+        }
+    }
+
+    // Caps the payload of every segment sent from now on at `maxseg` bytes (at least one). This
+    // can only shrink segments: asking for more than the peer's MSS or the path allows gets what
+    // was negotiated. Every sender (new data, retransmissions, persist probes) sizes its segments
+    // through max_segment_payload, so they all pick it up on their next segment.
+    pub fn set_maxseg(&mut self, maxseg: usize) {
+        self.maxseg = Some(cmp::max(maxseg, 1));
+    }
+
+    // Sets how early in every tick the connection gets to send: connections with a higher
+    // priority go first, so under contention for the link (or for the interface's memory
+    // budget, which the first to ask get) an interactive connection's small writes go out
+    // ahead of a bulk transfer's segments. All connections start out at 0; ties go in no
+    // particular order
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    // The MSS we announce: the most payload our own link can take in one segment, after the IP
+    // header and an option-less TCP header
+    fn local_mss(&self) -> usize {
+        LINK_MTU - self.ip.header_len() - etherparse::TCP_MINIMUM_HEADER_SIZE
+    }
+
+    // Updates the path MTU estimate, e.g. after an ICMP "fragmentation needed" for the connection.
+    // Only affects segments sent from now on.
+    pub fn set_path_mtu(&mut self, mtu: usize) {
+        self.path_mtu = Some(mtu.clamp(MIN_PATH_MTU, LINK_MTU));
+    }
+
+    // Lowers the path MTU estimate if the timeouts so far look like a PMTU black hole (see
+    // TcpConfig::blackhole_rtos): the oldest segment is full-sized, and it has gone this many
+    // retransmission timeouts without an ACK. Only timeouts since the last ACK that moved
+    // SND.UNA count, so a path that just loses the odd segment never gets here. The data that
+    // was in flight at the old size is lost as a whole; like after any timeout, it's resent a
+    // segment at a time from SND.UNA, now at the smaller size
+    fn detect_blackhole(&mut self, data_in_flight: usize) {
+        let Some(threshold) = self.config.blackhole_rtos else {
+            return;
+        };
+        let mss = self.max_segment_payload();
+        let floor = self.config.blackhole_mss;
+        if self.retransmits < threshold || data_in_flight < mss || mss <= floor {
+            return;
+        }
+        eprintln!(
+            "{} timeouts in a row with {} byte segments, suspecting a PMTU black hole; \
+             dropping to {} byte segments",
+            self.retransmits, mss, floor
+        );
+        self.set_path_mtu(floor + self.ip.header_len() + etherparse::TCP_MINIMUM_HEADER_SIZE);
+    }
+
+    // Freezes the transfer in both directions without closing anything: we stop sending new
+    // data, and advertise a zero window so the peer stops too. Timers keep running, so
+    // unacknowledged data is still retransmitted. Takes effect on the next tick
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    // Undoes `pause`: the window opens back up and sending picks up where it left off
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    // How much of the send queue is out on the wire waiting for the peer to acknowledge it
+    pub fn unacked_bytes(&self) -> usize {
+        // SND.NXT also counts our SYN and FIN, which aren't in the queue
+        cmp::min(self.data_in_flight(), self.send_queue_len())
+    }
+
+    // The send buffer's marks, see TcpConfig::send_buffer
+    pub fn send_buffer(&self) -> BufferMarks {
+        self.config.send_buffer
+    }
+
+    pub fn set_send_buffer(&mut self, marks: BufferMarks) {
+        self.config.send_buffer = marks;
+        self.unacked.lock().unwrap().high_water = marks.high;
+    }
+
+    // Lowering the high-water mark below what's buffered plus the open window doesn't take back
+    // any of the window (the peer may already be sending into it), it just closes as the data
+    // arrives and doesn't open up again until the buffer has drained below the new mark
+    pub fn set_recv_buffer(&mut self, marks: BufferMarks) {
+        self.config.recv_buffer = marks;
+        self.incoming.lock().unwrap().low_water = marks.low;
+    }
+
+    // How much data is waiting for the application to read it
+    pub fn recv_buffered(&self) -> usize {
+        self.incoming.lock().unwrap().data.len()
+    }
+
+    // How much data is in the send queue, in flight or not
+    pub fn send_queue_len(&self) -> usize {
+        self.unacked.lock().unwrap().data.len()
+    }
+
+    // The connection's two buffers, for a TcpStream to share
+    pub(crate) fn buffers(&self) -> (Shared<RecvBuffer>, Shared<SendBuffer>) {
+        (self.incoming.clone(), self.unacked.clone())
+    }
+
+    // How much of the send queue has been written by the application but not sent yet
+    pub fn queued_bytes(&self) -> usize {
+        self.send_queue_len() - self.unacked_bytes()
+    }
+
+    // The buffer memory the connection holds, or may have to at any moment: everything queued in
+    // either direction, plus the receive window, which the peer can fill without asking
+    pub fn memory_committed(&self) -> usize {
+        self.recv_buffered() + self.urgent.len() + self.send_queue_len() + self.recv.wnd as usize
+    }
+
+    // Whether the peer has closed its side of the connection, i.e. whether there'll be no more
+    // data to read once `incoming` is drained
+    pub fn is_rcv_closed(&self) -> bool {
+        match self.state {
+            State::CloseWait | State::Closing | State::TimeWait | State::LastAck => true,
+            State::Closed => self.close_reason == Some(CloseReason::Graceful),
+            _ => false,
+        }
+    }
+
+    // Function to handle incoming packets once a connection is established
+    pub fn on_packet<'a>(
+        &mut self,
+        nic: &mut Nic,
+        ipv4_header: etherparse::Ipv4HeaderSlice<'a>,
+        tcp_header: etherparse::TcpHeaderSlice<'a>,
+        tcp_payload: &'a [u8],
+    ) -> io::Result<()> {
+        // Log metadata of packet
+        eprintln!(
+            "{}:{} -> {}:{} {}b of TCP",
+            ipv4_header.source_addr(),
+            tcp_header.source_port(),
+            ipv4_header.destination_addr(),
+            tcp_header.destination_port(),
+            tcp_payload.len()
+        );
+
+        if let State::Closed = self.state {
+            // As far as we're concerned the connection no longer exists
+            return send_reset(nic, &ipv4_header, &tcp_header, tcp_payload);
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(
+                false,
+                SegmentSummary::of_slice(&tcp_header),
+                tcp_payload.len(),
+            );
+        }
+        self.bytes_received += tcp_payload.len() as u64;
+        // Whatever the segment is, the peer is evidently still there
+        self.last_heard = Instant::now();
+        self.keepalive_probes_sent = 0;
+
+        if let State::SynSent = self.state {
+            return self.on_syn_sent_segment(nic, &ipv4_header, &tcp_header, tcp_payload);
+        }
+
+        // A new SYN that didn't make way for a new incarnation (see reincarnation_iss) is an
+        // old duplicate, or one we've been told to treat as such (TcpConfig::time_wait_syn).
+        // Either way it has no business with this connection, and whatever its sequence number
+        // it gets the most an unacceptable segment gets: an ACK, or nothing at all
+        let new_syn = tcp_header.syn() && !tcp_header.ack() && !tcp_header.rst();
+        if matches!(self.state, State::TimeWait) && new_syn {
+            let drop = self.config.time_wait_syn == TimeWaitSyn::Drop;
+            eprintln!(
+                "Old duplicate SYN from {}:{} in TIME-WAIT, {}",
+                ipv4_header.source_addr(),
+                tcp_header.source_port(),
+                if drop {
+                    "dropping it"
+                } else {
+                    "acknowledging it"
+                },
+            );
+            if !drop {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(());
+        }
+
+        // First, check that the sequence numbers are valid (RFC 793 Sec. 3.3)
+        // SYN and FIN each occupy one sequence number in addition to the payload bytes
+        let seq_number = tcp_header.sequence_number();
+        let mut seg_len = tcp_payload.len() as u32;
+        if tcp_header.syn() {
+            seg_len += 1;
+        }
+        if tcp_header.fin() {
+            seg_len += 1;
+        }
+        let acceptable = self.is_seq_acceptable(seq_number, seg_len);
+
+        // With a closed receive window no segment carrying data is acceptable, but RFC 793 says
+        // special allowance should be made for valid ACKs: a segment at exactly RCV.NXT still has
+        // its ACK and window fields processed, and only its payload is dropped. Otherwise a full
+        // receive buffer would stop us from learning that the peer can take more of our data.
+        let zero_window_ack = !acceptable && self.recv.wnd == 0 && seq_number == self.recv.nxt;
+
+        if !acceptable && !zero_window_ack {
+            // A FIN ending right before RCV.NXT is one we've already taken: the peer is
+            // retransmitting it because our ACK of it got lost
+            let duplicate_fin = tcp_header.fin()
+                && self.is_rcv_closed()
+                && seq_number
+                    .wrapping_add(tcp_payload.len() as u32)
+                    .wrapping_add(1)
+                    == self.recv.nxt;
+            if duplicate_fin {
+                eprintln!(
+                    "Duplicate FIN from {}:{} in {}, acknowledging it again",
+                    ipv4_header.source_addr(),
+                    tcp_header.source_port(),
+                    self.state_name(),
+                );
+            }
+            // Likewise a SYN-ACK at the peer's ISN on a connection we opened: the peer is
+            // retransmitting it because the ACK that completed our handshake got lost. We're
+            // established already, and stay that way
+            let duplicate_syn_ack = tcp_header.syn()
+                && tcp_header.ack()
+                && !self.passive
+                && seq_number == self.recv.irs;
+            if duplicate_syn_ack {
+                eprintln!(
+                    "Duplicate SYN-ACK from {}:{} in {}, acknowledging it again",
+                    ipv4_header.source_addr(),
+                    tcp_header.source_port(),
+                    self.state_name(),
+                );
+            }
+            // An unacceptable segment gets an ACK in reply (unless it's a RST) and is dropped.
+            // For a duplicate FIN or SYN-ACK that ACK is exactly what the peer is missing
+            if !tcp_header.rst() {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            // In TIME-WAIT, the peer has to hear our ACK before we can go: start waiting all
+            // over again (RFC 793 Sec. 3.9)
+            if matches!(self.state, State::TimeWait) && tcp_header.fin() {
+                self.enter_time_wait();
+            }
+            return Ok(());
+        }
+
+        // An acceptable RST means the peer has abandoned the connection (RFC 793 Sec. 3.4)
+        if tcp_header.rst() {
+            // But only one exactly at RCV.NXT is taken at its word (RFC 5961 Sec. 3.2). Anywhere
+            // else in the window it's as likely to be a blind attacker's guess, which only needs
+            // to land somewhere in a 64KiB window rather than on the one right number. It gets a
+            // challenge ACK instead: a peer that really reset the connection answers that with
+            // a RST at exactly the sequence number the ACK tells it, and an attacker never sees it
+            if seq_number != self.recv.nxt {
+                if !self.challenge_ack_allowed() {
+                    // Over the limit: the RST is dropped without a word
+                    return Ok(());
+                }
+                eprintln!(
+                    "RST from {}:{} at {} rather than RCV.NXT {}, sending a challenge ACK",
+                    ipv4_header.source_addr(),
+                    tcp_header.source_port(),
+                    seq_number,
+                    self.recv.nxt,
+                );
+                self.write(nic, self.send.nxt, 0)?;
+                return Ok(());
+            }
+            if matches!(self.state, State::TimeWait)
+                && self.config.time_wait_rst == TimeWaitRst::Ignore
+            {
+                eprintln!(
+                    "Ignoring RST from {}:{} in TIME-WAIT (RFC 1337)",
+                    ipv4_header.source_addr(),
+                    tcp_header.source_port(),
+                );
+                return Ok(());
+            }
+            eprintln!(
+                "{}:{} reset the connection",
+                ipv4_header.source_addr(),
+                tcp_header.source_port(),
+            );
+            self.close(CloseReason::Reset);
+            return Ok(());
+        }
+
+        self.on_ecn_signals(&ipv4_header, &tcp_header, !tcp_payload.is_empty());
+
+        // Segments without the ACK bit carry nothing more for us to act on yet
+        if !tcp_header.ack() {
+            return Ok(());
+        }
+        let ack_number = tcp_header.acknowledgment_number();
+
+        if let State::SynRcvd = self.state {
+            // An ACK of our SYN completes the three-way handshake: SND.UNA < SEG.ACK =< SND.NXT
+            if is_between_wrapped(self.send.una, ack_number, self.send.nxt.wrapping_add(1)) {
+                self.state = State::Estab;
+                // Our SYN is acknowledged (it carried no data, so nothing leaves the send queue)
+                self.send.una = ack_number;
+                self.rto = INITIAL_RTO;
+                self.retransmits = 0;
+                // Seed the window update bookkeeping from the handshake-completing segment. This
+                // is the first segment a negotiated window scale would apply to (the windows on
+                // both SYNs are never scaled, RFC 7323 Sec. 2.2), but we never offer scaling, so
+                // this window and every later one is taken as is, like the SYN's was
+                self.send.wnd = tcp_header.window_size();
+                self.send.wl1 = seq_number;
+                self.send.wl2 = ack_number;
+                // The peer may already have sent data along with this ACK (a request, say), or
+                // its pure ACK got lost and this is the first data segment after it, which
+                // acknowledges our SYN just as well. Either way the data goes through the
+                // receive path below like any other segment's, and through it, gets
+                // acknowledged
+            } else {
+                // Whatever this acknowledges, it isn't our SYN-ACK (RFC 793 Sec. 3.9)
+                eprintln!("Bad ACK {} in SYN-RCVD, sending RST", ack_number);
+                return send_reset(nic, &ipv4_header, &tcp_header, tcp_payload);
+            }
+        }
+
+        if let State::Estab
+        | State::CloseWait
+        | State::FinWait1
+        | State::FinWait2
+        | State::Closing
+        | State::LastAck
+        | State::TimeWait = self.state
+        {
+            // A duplicate ACK (RFC 5681 Sec. 2): one for SND.UNA again while we have something
+            // outstanding, carrying no data, no SYN or FIN and no change of window. Anything
+            // else acknowledging SND.UNA has a reason of its own to be sent. The peer sends one
+            // for every segment arriving out of order, so a run of them means a segment is
+            // missing while the ones after it get through. An ACK for less than SND.UNA is
+            // simply old, and moves nothing: SND.UNA only ever advances. With the window
+            // closed, the same ACK over and over is just the peer answering our persist
+            // probes, not a sign of loss
+            let duplicate = ack_number == self.send.una
+                && self.send.una != self.send.nxt
+                && tcp_payload.is_empty()
+                && !tcp_header.fin()
+                && tcp_header.window_size() == self.send.wnd
+                && self.send.wnd != 0;
+
+            // Advance SND.UNA if the ACK covers new data: SND.UNA < SEG.ACK =< SND.NXT
+            // The acknowledged bytes are done with and leave the send queue
+            let advanced =
+                is_between_wrapped(self.send.una, ack_number, self.send.nxt.wrapping_add(1));
+            if advanced {
+                let acked = seq_offset(self.send.una, ack_number);
+                // The ACK may cover our SYN or FIN as well, which aren't in the queue
+                let acked_bytes = cmp::min(acked, self.send_queue_len());
+                self.unacked.lock().unwrap().data.drain(..acked_bytes);
+                self.bytes_acked += acked_bytes as u64;
+                self.send.una = ack_number;
+                let mss = self.max_segment_payload();
+                self.cc.on_ack(acked, mss);
+                // The peer is evidently receiving again, so stop backing off
+                self.rto = INITIAL_RTO;
+                self.retransmits = 0;
+                // Restart the retransmission timer for whatever is still outstanding (RFC 6298
+                // Sec. 5.3), so it gets a full RTO from now rather than what was left of the
+                // one started for the data just acknowledged. With nothing left outstanding,
+                // it's turned off below (Sec. 5.2)
+                if self.send.una != self.send.nxt && self.rto_deadline.is_some() {
+                    self.rto_deadline = Some(Instant::now() + self.rto);
+                }
+                self.dup_acks = 0;
+                if self
+                    .recover
+                    .is_some_and(|recover| !wrapping_lt(ack_number, recover))
+                {
+                    self.recover = None;
+                }
+            } else if duplicate {
+                self.on_duplicate_ack(nic)?;
+            }
+
+            // Update the send window, unless this segment is older than the one that last
+            // updated it: SND.WL1 < SEG.SEQ or (SND.WL1 = SEG.SEQ and SND.WL2 =< SEG.ACK)
+            // The ACK itself must also not be for data we haven't sent yet: SND.UNA =< SEG.ACK =< SND.NXT
+            let ack_in_range = is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ack_number,
+                self.send.nxt.wrapping_add(1),
+            );
+            let newer_segment = wrapping_lt(self.send.wl1, seq_number)
+                || (self.send.wl1 == seq_number && !wrapping_lt(ack_number, self.send.wl2));
+            if ack_in_range && newer_segment {
+                let was_closed = self.send.wnd == 0;
+                self.send.wnd = tcp_header.window_size();
+                self.send.wl1 = seq_number;
+                self.send.wl2 = ack_number;
+                if was_closed && self.send.wnd != 0 {
+                    self.on_window_reopened(nic)?;
+                }
+            }
+
+            // Data from the peer that acknowledges nothing new tells F-RTO nothing, only
+            // duplicate ACKs do
+            if self.frto.is_some() && (advanced || tcp_payload.is_empty()) {
+                self.frto_on_ack(nic, advanced, ack_number)?;
+            }
+        }
+
+        // Everything we've sent is acknowledged, so there's nothing left to retransmit
+        if self.send.una == self.send.nxt {
+            self.rto_deadline = None;
+
+            // Including our FIN, if we've sent one
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(),
+                State::LastAck => {
+                    self.close(CloseReason::Graceful);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        if zero_window_ack {
+            // We had no room for the payload, so drop it and re-advertise our (closed) window
+            self.write(nic, self.send.nxt, 0)?;
+            return Ok(());
+        }
+
+        // Whether the segment was new, in-order data that we took all of, whose ACK may wait
+        let mut delayable = false;
+
+        // The peer may keep sending until it has closed its side, even after we've closed ours
+        // (a half-close), so FIN-WAIT-1 and FIN-WAIT-2 take data just like ESTABLISHED does
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            // Deliver the segment's data to the application. Only data continuing exactly where
+            // RCV.NXT left off can be delivered: whatever part of a retransmission we already
+            // have is skipped, and segments starting beyond RCV.NXT are held until the gap in
+            // front of them has been filled
+            let in_order = !wrapping_lt(self.recv.nxt, seq_number);
+            // Data filling (part of) a hole is acknowledged right away (RFC 5681 Sec. 4.2), so
+            // the peer learns as soon as possible how far it got us
+            let fills_hole = !self.held.is_empty();
+            if !in_order && !tcp_payload.is_empty() {
+                self.hold_out_of_order(&mut nic.buffers, seq_number, tcp_payload, &tcp_header);
+            }
+            if in_order && !tcp_payload.is_empty() {
+                let already_received = seq_offset(seq_number, self.recv.nxt);
+                if already_received < tcp_payload.len() && self.rd_shut {
+                    let new_data = &tcp_payload[already_received..];
+                    if self.config.read_shutdown == ReadShutdown::Reset {
+                        eprintln!("Data arrived after the read side was shut down, resetting");
+                        return self.abort(nic, CloseReason::Reset);
+                    }
+                    // Take the data as if it had been read straight away: acknowledged, and
+                    // taking up no room in the window
+                    let accepted = cmp::min(new_data.len(), self.recv.wnd as usize);
+                    self.recv.nxt = self.recv.nxt.wrapping_add(accepted as u32);
+                    self.bytes_delivered += accepted as u64;
+                } else if already_received < tcp_payload.len() {
+                    let new_data = &tcp_payload[already_received..];
+                    // Don't take more than we advertised room for. A peer ignoring our window
+                    // has the excess dropped, so `incoming` never outgrows what we offered.
+                    // Taking only part of the segment makes its ACK go out right away (it
+                    // isn't delayable), telling the peer where we stopped and what's left
+                    let accepted = cmp::min(new_data.len(), self.recv.wnd as usize);
+                    // With URG set, the urgent pointer marks where in sequence space the urgent
+                    // data ends (see UrgentPointer), and what of it is new starts at RCV.NXT.
+                    // The urgent bytes are kept apart from the stream, to be read out-of-band
+                    // like with MSG_OOB
+                    let urgent_len = if tcp_header.urg() {
+                        let urgent_end = self.urgent_end(seq_number, tcp_header.urgent_pointer());
+                        if wrapping_lt(self.recv.nxt, urgent_end) {
+                            cmp::min(seq_offset(self.recv.nxt, urgent_end), accepted)
+                        } else {
+                            0
+                        }
+                    } else {
+                        0
+                    };
+                    self.urgent.extend(&new_data[..urgent_len]);
+                    let mut incoming = self.incoming.lock().unwrap();
+                    incoming.data.extend(&new_data[urgent_len..accepted]);
+                    if tcp_header.psh() {
+                        incoming.pushed = incoming.data.len();
+                    }
+                    drop(incoming);
+                    self.recv.nxt = self.recv.nxt.wrapping_add(accepted as u32);
+                    self.bytes_delivered += accepted as u64;
+                    // The data takes up buffer space, so the window shrinks by as much. Its
+                    // right edge (RCV.NXT + RCV.WND) stays where it was
+                    self.recv.wnd -= accepted as u16;
+                    if already_received == 0 && accepted == tcp_payload.len() && !fills_hole {
+                        delayable = true;
+                        self.unacked_rcv_bytes += accepted;
+                    }
+                }
+                self.take_reassembled(&mut nic.buffers);
+            }
+        }
+
+        // The peer is done sending once we've received everything up to its FIN. RCV.NXT only
+        // moves past data that's contiguous, so a FIN with a hole in front of it (its segment
+        // arrived out of order, or only part of its data fit in the window) isn't at RCV.NXT
+        // and isn't taken. Only the data of out-of-order segments is held, not their FIN, so
+        // it's the peer's retransmission, once the hole is filled, that gets us to the end of
+        // the stream
+        let fin_seq = seq_number.wrapping_add(tcp_payload.len() as u32);
+        let mut fin_taken = false;
+        if tcp_header.fin() && fin_seq == self.recv.nxt {
+            let next_state = match self.state {
+                State::Estab => Some(State::CloseWait),
+                // Both sides are closing at once, and our FIN isn't acknowledged yet
+                State::FinWait1 => Some(State::Closing),
+                // Also where we are if this same segment acknowledged our FIN in FIN-WAIT-1:
+                // the ACK was processed first and took us to FIN-WAIT-2, so a segment carrying
+                // both the ACK of our FIN and the peer's FIN goes straight to TIME-WAIT
+                State::FinWait2 => Some(State::TimeWait),
+                _ => None,
+            };
+            if let Some(next_state) = next_state {
+                // The FIN consumes a sequence number
+                self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                fin_taken = true;
+                if let State::TimeWait = next_state {
+                    self.enter_time_wait();
+                } else {
+                    self.state = next_state;
+                }
+            }
+        }
+
+        // Anything that occupied sequence space gets acknowledged, but not right away: the
+        // packet loop sends a single ACK once it's done with the whole batch of segments it read
+        // (see `flush_ack`), which covers every segment of the batch at once. Unless the peer
+        // needs to hear from us quickly (its FIN, data we're missing some of or had already,
+        // ...), that ACK may be delayed further. A FIN we've just taken only waits if the
+        // config says so, and if whatever data came with it could have waited too
+        if seg_len > 0 {
+            self.ack_pending = true;
+            let may_wait = if tcp_header.fin() {
+                fin_taken && self.config.delay_fin_ack && (delayable || tcp_payload.is_empty())
+            } else {
+                delayable
+            };
+            if !may_wait || tcp_header.syn() {
+                self.ack_now = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Handles a segment arriving while we wait for the answer to our SYN (RFC 793 Sec. 3.9,
+    // "If the state is SYN-SENT"). We know nothing about the peer's sequence space yet, so the
+    // usual acceptability test doesn't apply: the ACK is what tells us whether the segment is
+    // a reply to our SYN at all.
+    fn on_syn_sent_segment(
+        &mut self,
+        nic: &mut Nic,
+        ipv4_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        tcp_payload: &[u8],
+    ) -> io::Result<()> {
+        // The only thing the peer can acknowledge is our SYN: ISS < SEG.ACK =< SND.NXT. Any
+        // other ACK comes from a confused or spoofing peer, or from an old connection, and
+        // gets a RST (unless it is one itself). Our connection attempt carries on regardless.
+        let ack_acceptable = tcp_header.ack()
+            && is_between_wrapped(
+                self.send.iss,
+                tcp_header.acknowledgment_number(),
+                self.send.nxt.wrapping_add(1),
+            );
+        if tcp_header.ack() && !ack_acceptable {
+            eprintln!(
+                "{}:{} acknowledged {} but our SYN was {}, rejecting",
+                ipv4_header.source_addr(),
+                tcp_header.source_port(),
+                tcp_header.acknowledgment_number(),
+                self.send.iss,
+            );
+            return send_reset(nic, ipv4_header, tcp_header, tcp_payload);
+        }
+
+        if tcp_header.rst() {
+            // Only a RST acknowledging our SYN refuses the connection; others are ignored
+            if ack_acceptable {
+                eprintln!(
+                    "{}:{} refused the connection",
+                    ipv4_header.source_addr(),
+                    tcp_header.source_port(),
+                );
+                self.close(CloseReason::Reset);
+            }
+            return Ok(());
+        }
+
+        // Other than a RST, only a SYN (with our SYN acknowledged or not) means anything here.
+        // Data or a plain ACK can't be part of this connection yet, as it has no receive sequence
+        // space to put them in, so they're dropped and we go on waiting for the SYN-ACK
+        if !tcp_header.syn() {
+            eprintln!(
+                "Dropping {} byte segment without SYN from {}:{} in SYN-SENT",
+                tcp_payload.len(),
+                ipv4_header.source_addr(),
+                tcp_header.source_port(),
+            );
+            return Ok(());
+        }
+        self.recv.irs = tcp_header.sequence_number();
+        self.recv.nxt = tcp_header.sequence_number().wrapping_add(1);
+        // Unscaled, whether or not the SYN-ACK has a Window Scale option: our SYN never offers
+        // one, so scaling is off in both directions (see `accept`)
+        self.send.wnd = tcp_header.window_size();
+        self.send.wl1 = tcp_header.sequence_number();
+        self.send.wl2 = tcp_header.acknowledgment_number();
+        self.peer_mss = parse_peer_mss(tcp_header, &self.config);
+        self.peer_window_scale = parse_window_scale(tcp_header);
+        self.cc = congestion::from_name(self.cc.name(), self.max_segment_payload())?;
+
+        if ack_acceptable {
+            // A SYN-ACK for our SYN: we're connected, and only need to acknowledge its SYN
+            self.send.una = tcp_header.acknowledgment_number();
+            self.rto = INITIAL_RTO;
+            self.retransmits = 0;
+            self.rto_deadline = None;
+            self.state = State::Estab;
+            self.tcp.ack = true;
+            self.write(nic, self.send.nxt, 0)?;
+        } else {
+            // A bare SYN: the peer is opening a connection to us at the same time (simultaneous
+            // open). Answer with a SYN-ACK for it, and wait for the ACK of our SYN in SYN-RCVD
+            self.state = State::SynRcvd;
+            self.tcp.syn = true;
+            self.tcp.ack = true;
+            self.write(nic, self.send.una, 0)?;
+        }
+        Ok(())
+    }
+
+    // The state the connection is in. A copy: it doesn't follow the connection's later moves
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    // The name of the state the connection is in, as in `State`
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            State::Closed => "Closed",
+            State::Listen => "Listen",
+            State::SynSent => "SynSent",
+            State::SynRcvd => "SynRcvd",
+            State::Estab => "Estab",
+            State::FinWait1 => "FinWait1",
+            State::FinWait2 => "FinWait2",
+            State::Closing => "Closing",
+            State::TimeWait => "TimeWait",
+            State::CloseWait => "CloseWait",
+            State::LastAck => "LastAck",
+        }
+    }
+
+    // The CRC-32 of every byte delivered to the application so far, in order. Compare it with
+    // a Crc32 the sender kept over what it wrote to check nothing got corrupted, lost or
+    // duplicated on the way
+    #[cfg(feature = "delivered-digest")]
+    pub fn delivered_digest(&self) -> u32 {
+        self.incoming.lock().unwrap().digest.value()
+    }
+
+    // Where the sequence spaces stand, see seq_trace
+    #[cfg(feature = "seq-trace")]
+    pub(crate) fn seq_spaces(&self) -> SeqSpaces {
+        SeqSpaces {
+            snd_una: self.send.una,
+            snd_nxt: self.send.nxt,
+            snd_wnd: self.send.wnd,
+            rcv_nxt: self.recv.nxt,
+            rcv_wnd: self.recv.wnd,
+        }
+    }
+
+    // The window scaling in effect and the windows currently in use, in bytes
+    pub fn window_info(&self) -> WindowInfo {
+        WindowInfo {
+            // Scaling takes both SYNs carrying the option (RFC 7323 Sec. 1.3), and ours never
+            // does, so no shift applies in either direction
+            send_scale: 0,
+            recv_scale: 0,
+            peer_offered_scale: self.peer_window_scale,
+            send_window: self.send.wnd as usize,
+            recv_window: self.recv.wnd as usize,
+        }
+    }
+
+    // A snapshot of the connection's sequence spaces and buffers
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            state: self.state_name(),
+            snd_una: self.send.una,
+            snd_nxt: self.send.nxt,
+            snd_wnd: self.send.wnd,
+            rcv_nxt: self.recv.nxt,
+            rcv_wnd: self.recv.wnd,
+            bytes_in_flight: self.send.nxt.wrapping_sub(self.send.una),
+            send_queued: self.send_queue_len(),
+            recv_buffered: self.recv_buffered(),
+            rto_ms: self.rto.as_millis(),
+            congestion_control: self.cc.name(),
+            cwnd: self.cc.window(),
+            dup_acks: self.dup_acks,
+            bytes_sent: self.bytes_sent,
+            bytes_retransmitted: self.bytes_retransmitted,
+            bytes_acked: self.bytes_acked,
+            bytes_received: self.bytes_received,
+            bytes_delivered: self.bytes_delivered,
+        }
+    }
+
+    // The connection's segments as a sequence diagram, if it records them
+    pub fn sequence_diagram(&self) -> Option<String> {
+        let local = (Ipv4Addr::from(self.ip.source), self.tcp.source_port);
+        let remote = (
+            Ipv4Addr::from(self.ip.destination),
+            self.tcp.destination_port,
+        );
+        self.trace.as_ref().map(|trace| trace.render(local, remote))
+    }
+
+    // Formats the full TCB (Transmission Control Block) as a human-readable, multi-line string.
+    // Meant for teaching and debugging; it's richer than a derived Debug and lays the sequence
+    // spaces out side by side with the RFC 793 variable names.
+    //
+    // This formats into a single pre-sized String, but it does allocate, so it must not be called
+    // from a signal handler. Signal handlers should only set a flag, and the main loop should
+    // call this in response (see the SIGUSR1 handling in main).
+    pub fn debug_dump(&self) -> String {
+        let mut out = String::with_capacity(256);
+        // Writing to a String can't fail, so the fmt::Results are safe to ignore
+        let _ = writeln!(out, "state: {:?}", self.state);
+        let _ = writeln!(
+            out,
+            "send: SND.UNA={} SND.NXT={} SND.WND={} ISS={} SND.WL1={} SND.WL2={} SND.UP={}",
+            self.send.una,
+            self.send.nxt,
+            self.send.wnd,
+            self.send.iss,
+            self.send.wl1,
+            self.send.wl2,
+            self.send.up,
+        );
+        let _ = writeln!(
+            out,
+            "recv: RCV.NXT={} RCV.WND={} IRS={} RCV.UP={}",
+            self.recv.nxt, self.recv.wnd, self.recv.irs, self.recv.up,
+        );
+        let _ = writeln!(
+            out,
+            "in flight: {} bytes",
+            self.send.nxt.wrapping_sub(self.send.una)
+        );
+        let _ = writeln!(
+            out,
+            "congestion control: {}, cwnd={} bytes, {} duplicate ACKs",
+            self.cc.name(),
+            self.cc.window(),
+            self.dup_acks,
+        );
+        // How long until each timer goes off, "-" for one that isn't running
+        let now = Instant::now();
+        let left = |deadline: Option<Instant>| match deadline {
+            Some(deadline) => format!("{:?}", deadline.saturating_duration_since(now)),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "timers: RTO={} persist={} delayed ACK={} keep-alive={} TIME-WAIT={}",
+            left(self.rto_deadline),
+            left(self.persist_deadline),
+            left(self.ack_deadline),
+            left(self.keepalive_deadline()),
+            left(self.time_wait_deadline),
+        );
+        let _ = writeln!(
+            out,
+            "buffered: {} bytes to send, {} bytes to read, {} bytes held out of order",
+            self.send_queue_len(),
+            self.recv_buffered(),
+            self.held.covered(),
+        );
+        let _ = write!(out, "options: peer MSS={}", self.peer_mss);
+        if let Some(maxseg) = self.maxseg {
+            let _ = write!(out, " maxseg={}", maxseg);
+        }
+        match self.path_mtu {
+            Some(mtu) => {
+                let _ = write!(out, " path MTU={}", mtu);
+            }
+            None => out.push_str(" path MTU=unknown"),
+        }
+        match &self.ecn {
+            Some(ecn) => {
+                let _ = write!(
+                    out,
+                    " ECN=on (echoing ECE={}, CWR pending={}, violations={})",
+                    ecn.echo_ece, ecn.send_cwr, ecn.violations,
+                );
+            }
+            None => out.push_str(" ECN=off"),
+        }
+        out
+    }
+
+    // The sequence number right after the urgent data of a segment starting at `seq_number`.
+    // The pointer is added modulo 2^32 like any offset in sequence space, so urgent data
+    // reaching across 2^32 - 1 ends at a small sequence number, past the start rather than
+    // before it
+    fn urgent_end(&self, seq_number: u32, urgent_pointer: u16) -> u32 {
+        let pointed_at = seq_number.wrapping_add(urgent_pointer as u32);
+        match self.config.urgent_pointer {
+            UrgentPointer::FollowingByte => pointed_at,
+            UrgentPointer::LastByte => pointed_at.wrapping_add(1),
+        }
+    }
+
+    // Holds on to the data of a segment that starts beyond RCV.NXT, for when the hole in front
+    // of it is filled. Only what lies within the window is kept, so the held data never takes
+    // more room than we offered, and only what we don't have yet, so a retransmission of
+    // something already held adds nothing. Urgent data is left for the peer to retransmit in
+    // order, since the urgent pointer only makes sense relative to the stream delivered so far.
+    // The pieces are kept in buffers from the interface's pool, and go back to it once taken
+    fn hold_out_of_order(
+        &mut self,
+        buffers: &mut BufferPool,
+        seq_number: u32,
+        payload: &[u8],
+        tcp_header: &etherparse::TcpHeaderSlice,
+    ) {
+        if self.rd_shut || tcp_header.urg() {
+            return;
+        }
+        let offset = seq_offset(self.recv.nxt, seq_number);
+        let room = (self.recv.wnd as usize).saturating_sub(offset);
+        let kept = cmp::min(payload.len(), room);
+        let end = seq_number.wrapping_add(kept as u32);
+        for (start, stop) in self.held.gaps(seq_number, end) {
+            let from = seq_offset(seq_number, start);
+            let to = seq_offset(seq_number, stop);
+            let mut piece = buffers.take();
+            piece.extend_from_slice(&payload[from..to]);
+            self.held_data.push((start, piece, tcp_header.psh()));
+        }
+        self.held.insert(seq_number, end);
+    }
+
+    // Moves held data that RCV.NXT has caught up with into the stream: once a segment fills
+    // the hole in front of it, RCV.NXT jumps past everything that is now contiguous, and the
+    // ACK for the segment acknowledges all of it at once
+    fn take_reassembled(&mut self, buffers: &mut BufferPool) {
+        if self.held.is_empty() {
+            return;
+        }
+        // Whatever the segment just delivered overlapped is there already
+        let nxt = self.recv.nxt;
+        self.held.remove_before(nxt);
+        let mut i = 0;
+        while let Some((start, data, _)) = self.held_data.get_mut(i) {
+            let end = start.wrapping_add(data.len() as u32);
+            if !wrapping_lt(nxt, end) {
+                let (_, data, _) = self.held_data.swap_remove(i);
+                buffers.give(data);
+                continue;
+            }
+            if wrapping_lt(*start, nxt) {
+                data.drain(..seq_offset(*start, nxt));
+                *start = nxt;
+            }
+            i += 1;
+        }
+
+        // The pieces don't overlap and now all start at RCV.NXT or later, so [RCV.NXT, end) is
+        // made up of pieces each starting where the one before ended
+        let end = self.held.contiguous_from(nxt);
+        let mut at = nxt;
+        while at != end {
+            let i = self
+                .held_data
+                .iter()
+                .position(|(start, _, _)| *start == at)
+                .unwrap();
+            let (_, data, psh) = self.held_data.swap_remove(i);
+            if !self.rd_shut {
+                let mut incoming = self.incoming.lock().unwrap();
+                incoming.data.extend(&data);
+                if psh {
+                    incoming.pushed = incoming.data.len();
+                }
+                drop(incoming);
+                self.recv.wnd = self.recv.wnd.saturating_sub(data.len() as u16);
+            }
+            self.bytes_delivered += data.len() as u64;
+            at = at.wrapping_add(data.len() as u32);
+            buffers.give(data);
+        }
+        self.held.remove_before(end);
+        self.recv.nxt = end;
+    }
+
+    // Segment acceptability test (RFC 793 Sec. 3.3 "Segment Arrival"):
+    //
+    //  Segment Length | Receive Window | Test
+    //  ---------------|----------------|-------------------------------------------------
+    //        0        |       0        | SEG.SEQ = RCV.NXT
+    //        0        |      >0        | RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+    //       >0        |       0        | not acceptable
+    //       >0        |      >0        | RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+    //                 |                |   or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+    //
+    // Zero-length segments (pure ACKs, window updates) must still be accepted when our receive
+    // window is closed, otherwise the peer could never tell us anything while our buffer is full.
+    //
+    // The window's right edge RCV.NXT+RCV.WND is the first sequence number outside it: a segment
+    // whose last byte is RCV.NXT+RCV.WND-1 is acceptable, while one starting at RCV.NXT+RCV.WND
+    // isn't, and (with an open window) neither is a zero-length segment there. A segment that
+    // starts inside the window but runs past its edge is acceptable, and the part that doesn't
+    // fit is trimmed off when its data is taken.
+    fn is_seq_acceptable(&self, seq_number: u32, seg_len: u32) -> bool {
+        let window_start = self.recv.nxt;
+        let window_end = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+
+        if seg_len == 0 {
+            if self.recv.wnd == 0 {
+                seq_number == window_start
+            } else {
+                is_between_wrapped(window_start.wrapping_sub(1), seq_number, window_end)
+            }
+        } else if self.recv.wnd == 0 {
+            false
+        } else {
+            let last_byte = seq_number.wrapping_add(seg_len - 1);
+            is_between_wrapped(window_start.wrapping_sub(1), seq_number, window_end)
+                || is_between_wrapped(window_start.wrapping_sub(1), last_byte, window_end)
+        }
+    }
+}
+
+// Replies to a segment that doesn't belong to any connection with a RST
+// (RFC 793 Sec. 3.4 "Reset Generation"). Incoming RSTs are never answered.
+//
+//  If the incoming segment has an ACK field, the reset takes its sequence number from the ACK
+//  field of the segment, otherwise the reset has sequence number zero and the ACK field is set to
+//  the sum of the sequence number and segment length of the incoming segment.
+pub fn send_reset(
+    nic: &mut Nic,
+    ipv4_header: &etherparse::Ipv4HeaderSlice,
+    tcp_header: &etherparse::TcpHeaderSlice,
+    tcp_payload: &[u8],
+) -> io::Result<()> {
+    if tcp_header.rst() {
+        return Ok(());
+    }
+
+    let mut tcp = etherparse::TcpHeader::new(
+        tcp_header.destination_port(),
+        tcp_header.source_port(),
+        0,
+        0,
+    );
+    tcp.rst = true;
+    if tcp_header.ack() {
+        tcp.sequence_number = tcp_header.acknowledgment_number();
+    } else {
+        let mut seg_len = tcp_payload.len() as u32;
+        if tcp_header.syn() {
+            seg_len += 1;
+        }
+        if tcp_header.fin() {
+            seg_len += 1;
+        }
+        tcp.ack = true;
+        tcp.acknowledgment_number = tcp_header.sequence_number().wrapping_add(seg_len);
+    }
+
+    let mut ip = etherparse::Ipv4Header::new(
+        0,
+        64,
+        etherparse::IpNumber::Tcp as u8,
+        ipv4_header.destination(),
+        ipv4_header.source(),
+    );
+    ip.identification = ip_id::next_stateless();
+    send_segment(nic, &mut ip, &mut tcp, &[])
+}
+
+// Whether a SYN asks for ECN, i.e. whether we agree to classic ECN (RFC 3168 Sec. 6.1.1), the
+// only kind we speak. What the peer supports shows in three flags of its SYN, AE (the former
+// NS bit), CWR and ECE:
+//
+//  - CWR and ECE: classic ECN. Peers doing the ECN nonce (RFC 3540, now historic) ask the same
+//    way, and add the nonce in NS on their data; we never set NS, which to the peer is just a
+//    receiver that doesn't do the nonce, and we don't check it
+//  - AE, CWR and ECE: Accurate ECN (RFC 9768). Our SYN-ACK (ECE alone, AE never set) is what a
+//    classic ECN server answers, and tells the peer to fall back to classic ECN
+//  - anything else: no ECN, including the combinations AccECN leaves for future use
+fn ecn_setup_requested(tcp_header: &etherparse::TcpHeaderSlice) -> bool {
+    tcp_header.cwr() && tcp_header.ece()
+}
+
+// Frames a segment made of the given headers and payload for the TUN device and sends it,
+// filling in the IP payload length and the TCP checksum
+pub(crate) fn send_segment(
+    nic: &mut Nic,
+    ip: &mut etherparse::Ipv4Header,
+    tcp: &mut etherparse::TcpHeader,
+    payload: &[u8],
+) -> io::Result<()> {
+    ip.set_payload_len(tcp.header_len() as usize + payload.len())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    tcp.checksum = tcp
+        .calc_checksum_ipv4(ip, payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut buf = nic.buffers.take();
+    // TUN frame prefix: no flags, IPv4
+    buf.extend_from_slice(&[0, 0, 0x08, 0x00]);
+    ip.write(&mut buf).map_err(io::Error::other)?;
+    tcp.write(&mut buf)?;
+    buf.extend_from_slice(payload);
+    nic.send_buffer(buf)?;
+    Ok(())
+}
+
+// The TCP checksum of just the pseudo-header (RFC 793 Sec. 3.1) of a segment `tcp_len` bytes
+// long, header included: the ones' complement sum of the addresses, the protocol and the
+// length, not yet complemented. It's what the checksum field holds for the kernel to start from
+// when it's to finish the checksum (see Nic::send_segments)
+fn pseudo_header_checksum(ip: &etherparse::Ipv4Header, tcp_len: usize) -> u16 {
+    let address_words = ip.source.chunks(2).chain(ip.destination.chunks(2));
+    let mut sum: u32 = address_words
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    sum += ip.protocol as u32 + tcp_len as u32;
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// The MSS the peer announced on its SYN (or the default, RFC 879, if it didn't), raised to the
+// configured floor if it's smaller. A peer claiming it can only take a byte or two per segment
+// (or none) would have us crawl along in tiny segments, or never send at all
+fn parse_peer_mss(tcp_header: &etherparse::TcpHeaderSlice, config: &TcpConfig) -> usize {
+    let mss = tcp_header
+        .options_iterator()
+        .find_map(|option| match option {
+            Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss as usize),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_MSS);
+    if mss < config.min_peer_mss {
+        eprintln!(
+            "Peer MSS {} is below the minimum, using {}",
+            mss, config.min_peer_mss
+        );
+    }
+    cmp::max(mss, config.min_peer_mss)
+}
+
+// The shift count of the peer's Window Scale option, if its SYN has one. A shift beyond
+// MAX_WINDOW_SCALE is taken for MAX_WINDOW_SCALE, as RFC 7323 Sec. 2.3 has it
+fn parse_window_scale(tcp_header: &etherparse::TcpHeaderSlice) -> Option<u8> {
+    let shift = tcp_header
+        .options_iterator()
+        .find_map(|option| match option {
+            Ok(etherparse::TcpOptionElement::WindowScale(shift)) => Some(shift),
+            _ => None,
+        })?;
+    if shift > MAX_WINDOW_SCALE {
+        eprintln!(
+            "Window scale {} is beyond the maximum, using {}",
+            shift, MAX_WINDOW_SCALE
+        );
+    }
+    Some(cmp::min(shift, MAX_WINDOW_SCALE))
+}
+
+// The largest window we advertise: as much as the receive buffer holds, unless the configuration
+// caps it lower, and never more than the window field takes without scaling. We never offer
+// window scaling, so our own windows are unscaled on every connection, whatever the peer's SYN
+// has to say about scaling
+fn max_window(config: &TcpConfig) -> usize {
+    let buffer = config.recv_buffer.high;
+    let limit = config
+        .max_advertised_window
+        .map_or(buffer, |max| cmp::min(max, buffer));
+    cmp::min(limit, u16::MAX as usize)
+}
+
+// How far `to` is ahead of `from`, e.g. the number of bytes in flight between SND.UNA and
+// SND.NXT. `to` must not be behind `from`, or the distance would come out as nearly 2^32
+fn seq_offset(from: u32, to: u32) -> usize {
+    debug_assert!(
+        !wrapping_lt(to, from),
+        "sequence number {} is behind {}",
+        to,
+        from
+    );
+    to.wrapping_sub(from) as usize
+}
+
+// A fresh initial send sequence number for a new connection. One that's easy to guess (like
+// always starting at 0) lets a blind attacker land segments in the connection without ever
+// seeing any of it, so it's picked at random instead, from RandomState like IpIds' seed
+// (RFC 6528 Sec. 3)
+pub fn initial_sequence_number() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+// Sequence numbers live in a 32-bit space that wraps around, so comparisons must be done modulo
+// 2^32 (RFC 1323 Sec. 4.2): `lhs` is "less than" `rhs` if the distance from `rhs` to `lhs` is
+// negative when interpreted as a signed 32-bit offset. Plain comparisons go wrong near the wrap.
+fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+    (lhs.wrapping_sub(rhs) as i32) < 0
+}
+
+// Checks start < x < end in wrapping sequence space
+fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
+    wrapping_lt(start, x) && wrapping_lt(x, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapping_lt_across_the_wrap() {
+        assert!(wrapping_lt(0xFFFF_FFFF, 0));
+        assert!(!wrapping_lt(0, 0xFFFF_FFFF));
+        assert!(wrapping_lt(0xFFFF_FFF0, 0x10));
+        assert!(!wrapping_lt(5, 5));
+        // Up to half the sequence space ahead is "after", anything further is "before"
+        assert!(wrapping_lt(0, 0x7FFF_FFFF));
+        assert!(!wrapping_lt(0, 0x8000_0001));
+        assert!(wrapping_lt(0x8000_0001, 0));
+    }
+
+    #[test]
+    fn is_between_wrapped_across_the_wrap() {
+        assert!(is_between_wrapped(0xFFFF_FFFE, 0xFFFF_FFFF, 1));
+        assert!(is_between_wrapped(0xFFFF_FFFE, 0, 1));
+        // Both ends are excluded
+        assert!(!is_between_wrapped(0xFFFF_FFFE, 0xFFFF_FFFE, 1));
+        assert!(!is_between_wrapped(0xFFFF_FFFE, 1, 1));
+        assert!(!is_between_wrapped(0xFFFF_FFFE, 2, 1));
+    }
+
+    // A SYN carrying just a Window Scale option with the given shift
+    fn syn_with_window_scale(shift: u8) -> Vec<u8> {
+        let mut tcp = etherparse::TcpHeader::new(1234, 80, 0, 1000);
+        tcp.syn = true;
+        tcp.set_options(&[etherparse::TcpOptionElement::WindowScale(shift)])
+            .unwrap();
+        let mut bytes = Vec::new();
+        tcp.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn window_scale_is_clamped_to_14() {
+        let syn = syn_with_window_scale(7);
+        let header = etherparse::TcpHeaderSlice::from_slice(&syn).unwrap();
+        assert_eq!(parse_window_scale(&header), Some(7));
+
+        let syn = syn_with_window_scale(20);
+        let header = etherparse::TcpHeaderSlice::from_slice(&syn).unwrap();
+        assert_eq!(parse_window_scale(&header), Some(14));
+    }
+
+    #[test]
+    fn seq_offset_across_the_wrap() {
+        assert_eq!(seq_offset(0xFFFF_FFFF, 0xFFFF_FFFF), 0);
+        assert_eq!(seq_offset(0xFFFF_FFFF, 0), 1);
+        assert_eq!(seq_offset(0xFFFF_FFF0, 0x10), 0x20);
+        assert_eq!(seq_offset(100, 1600), 1500);
+    }
+
+    // An established connection expecting `nxt` next, with a receive window of `wnd`
+    fn receiving(nxt: u32, wnd: u16) -> Connection {
+        let local = (Ipv4Addr::new(192, 168, 0, 1), 80);
+        let remote = (Ipv4Addr::new(192, 168, 0, 2), 40000);
+        let mut connection =
+            Connection::new(State::Estab, local, remote, 0, &TcpConfig::default(), None).unwrap();
+        connection.recv.nxt = nxt;
+        connection.recv.wnd = wnd;
+        connection
+    }
+
+    // Where the window starts in each test: well clear of the wrap, shortly before it (so the
+    // window spans it), and on the very last sequence number
+    const WINDOW_STARTS: [u32; 3] = [1000, 0xFFFF_FFF0, 0xFFFF_FFFF];
+
+    #[test]
+    fn acceptable_at_rcv_nxt() {
+        for nxt in WINDOW_STARTS {
+            let connection = receiving(nxt, 100);
+            assert!(connection.is_seq_acceptable(nxt, 0));
+            assert!(connection.is_seq_acceptable(nxt, 1));
+            assert!(connection.is_seq_acceptable(nxt, 100));
+            // Past the right edge, but it starts inside
+            assert!(connection.is_seq_acceptable(nxt, 101));
+            // Old data that runs into the window
+            assert!(connection.is_seq_acceptable(nxt.wrapping_sub(1), 2));
+            // and old data that doesn't
+            assert!(!connection.is_seq_acceptable(nxt.wrapping_sub(1), 1));
+            assert!(!connection.is_seq_acceptable(nxt.wrapping_sub(1), 0));
+        }
+    }
+
+    #[test]
+    fn acceptable_up_to_the_last_byte_of_the_window() {
+        for nxt in WINDOW_STARTS {
+            let connection = receiving(nxt, 100);
+            let last = nxt.wrapping_add(99);
+            assert!(connection.is_seq_acceptable(last, 0));
+            assert!(connection.is_seq_acceptable(last, 1));
+            // Ending on the last byte
+            assert!(connection.is_seq_acceptable(nxt.wrapping_add(90), 10));
+        }
+    }
+
+    #[test]
+    fn not_acceptable_at_the_right_edge() {
+        for nxt in WINDOW_STARTS {
+            let connection = receiving(nxt, 100);
+            let edge = nxt.wrapping_add(100);
+            assert!(!connection.is_seq_acceptable(edge, 0));
+            assert!(!connection.is_seq_acceptable(edge, 1));
+            assert!(!connection.is_seq_acceptable(edge.wrapping_add(1), 1));
+        }
+    }
+
+    #[test]
+    fn zero_window_only_takes_an_empty_segment_at_rcv_nxt() {
+        for nxt in WINDOW_STARTS {
+            let connection = receiving(nxt, 0);
+            assert!(connection.is_seq_acceptable(nxt, 0));
+            assert!(!connection.is_seq_acceptable(nxt, 1));
+            assert!(!connection.is_seq_acceptable(nxt.wrapping_add(1), 0));
+            assert!(!connection.is_seq_acceptable(nxt.wrapping_sub(1), 0));
+        }
+    }
+
+    // Hands `connection` a pure ACK from the peer, at `seq`, acknowledging `ack` and offering
+    // a window of `wnd`. Returns the frames the connection sent in answer
+    fn pure_ack(connection: &mut Connection, seq: u32, ack: u32, wnd: u16) -> Vec<Vec<u8>> {
+        let mut tcp = etherparse::TcpHeader::new(40000, 80, seq, wnd);
+        tcp.ack = true;
+        tcp.acknowledgment_number = ack;
+        let ip = etherparse::Ipv4Header::new(
+            tcp.header_len(),
+            64,
+            6,
+            [192, 168, 0, 2],
+            [192, 168, 0, 1],
+        );
+        let (mut ip_bytes, mut tcp_bytes) = (Vec::new(), Vec::new());
+        ip.write(&mut ip_bytes).unwrap();
+        tcp.write(&mut tcp_bytes).unwrap();
+
+        let mut nic = Nic::capture();
+        connection
+            .on_packet(
+                &mut nic,
+                etherparse::Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap(),
+                etherparse::TcpHeaderSlice::from_slice(&tcp_bytes).unwrap(),
+                &[],
+            )
+            .unwrap();
+        nic.take_captured()
+    }
+
+    // A connection whose receive window is closed, with 100 bytes sent and not yet acknowledged
+    fn zero_window_with_data_in_flight() -> Connection {
+        let mut connection = receiving(1000, 0);
+        connection.unacked.lock().unwrap().data.extend([0u8; 100]);
+        connection.send.nxt = connection.send.una.wrapping_add(100);
+        connection.send.wnd = 4000;
+        connection
+    }
+
+    #[test]
+    fn zero_window_takes_a_pure_ack_at_rcv_nxt() {
+        let mut connection = zero_window_with_data_in_flight();
+        let acked = connection.send.nxt;
+
+        let replies = pure_ack(&mut connection, 1000, acked, 8000);
+        // The ACK and the window are both taken, and an acceptable segment needs no answer
+        assert_eq!(connection.send.una, acked);
+        assert!(connection.send_queue_len() == 0);
+        assert_eq!(connection.send.wnd, 8000);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn zero_window_turns_down_a_pure_ack_past_rcv_nxt() {
+        let mut connection = zero_window_with_data_in_flight();
+        let (una, nxt) = (connection.send.una, connection.send.nxt);
+
+        let replies = pure_ack(&mut connection, 1001, nxt, 8000);
+        // Nothing of it is taken, and the peer is told where we are
+        assert_eq!(connection.send.una, una);
+        assert_eq!(connection.send_queue_len(), 100);
+        assert_eq!(connection.send.wnd, 4000);
+        assert_eq!(replies.len(), 1);
+    }
+
+    #[test]
+    fn pseudo_header_checksum_finishes_into_the_full_one() {
+        let ip = etherparse::Ipv4Header::new(0, 64, 6, [192, 168, 0, 1], [192, 168, 0, 2]);
+        let tcp = etherparse::TcpHeader::new(80, 40000, 0xFFFF_0000, 1000);
+        let payload = b"an odd number of bytes!";
+        let mut segment = Vec::new();
+        tcp.write(&mut segment).unwrap();
+        segment.extend_from_slice(payload);
+
+        // What the kernel does with it: add up the rest of the segment (the checksum field
+        // being zero) onto the pseudo-header's sum, and complement the result
+        let mut sum = pseudo_header_checksum(&ip, segment.len()) as u32;
+        for word in segment.chunks(2) {
+            sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+        }
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        assert_eq!(!(sum as u16), tcp.calc_checksum_ipv4(&ip, payload).unwrap());
+    }
+
+    // The value debug_dump gives each timer on its "timers:" line, in the order they're listed
+    fn dumped_timers(connection: &Connection) -> Vec<String> {
+        let dump = connection.debug_dump();
+        let line = dump
+            .lines()
+            .find(|line| line.starts_with("timers: "))
+            .unwrap();
+        line.split(' ')
+            .filter_map(|field| field.split_once('='))
+            .map(|(_, value)| value.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn debug_dump_shows_the_time_left_on_running_timers() {
+        let mut connection = receiving(1000, 8000);
+        assert_eq!(dumped_timers(&connection), ["-", "-", "-", "-", "-"]);
+
+        connection.rto_deadline = Some(Instant::now() + Duration::from_secs(3));
+        connection.time_wait_deadline = Some(Instant::now() + Duration::from_secs(60));
+        let timers = dumped_timers(&connection);
+        assert!(
+            timers[0].ends_with('s') && timers[0].starts_with('2'),
+            "{}",
+            timers[0]
+        );
+        assert_eq!(timers[1..4], ["-", "-", "-"]);
+        assert!(timers[4].starts_with("59."), "{}", timers[4]);
+    }
+
+    // Congestion control that always allows the same window, so a test decides the congestion
+    // window's side of fill_pipe's limit
+    struct FixedWindow(usize);
+
+    impl CongestionControl for FixedWindow {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+        fn window(&self) -> usize {
+            self.0
+        }
+        fn on_ack(&mut self, _: usize, _: usize) {}
+        fn on_congestion(&mut self, _: usize, _: usize) {}
+        fn on_timeout(&mut self, _: usize, _: usize) {}
+        fn undo_timeout(&mut self) {}
+    }
+
+    // A connection with `queued` bytes written (counting up from 0, so where each came from in
+    // the queue shows), the first `in_flight` of them already sent, and the given peer window and
+    // congestion window
+    fn sending(queued: usize, in_flight: usize, snd_wnd: u16, cwnd: usize) -> Connection {
+        let mut connection = receiving(1000, 8000);
+        connection
+            .unacked
+            .lock()
+            .unwrap()
+            .data
+            .extend((0..queued).map(|i| i as u8));
+        connection.send.nxt = connection.send.una.wrapping_add(in_flight as u32);
+        connection.send.wnd = snd_wnd;
+        connection.cc = Box::new(FixedWindow(cwnd));
+        connection
+    }
+
+    // Runs fill_pipe, and returns what it says it sent along with the payloads that went out,
+    // one after the other
+    fn fill(connection: &mut Connection) -> (usize, Vec<u8>) {
+        let mut nic = Nic::capture();
+        let sent = connection.fill_pipe(&mut nic).unwrap();
+        let mut payloads = Vec::new();
+        for frame in nic.take_captured() {
+            let ip = etherparse::Ipv4HeaderSlice::from_slice(&frame[4..]).unwrap();
+            let tcp =
+                etherparse::TcpHeaderSlice::from_slice(&frame[4 + ip.slice().len()..]).unwrap();
+            payloads.extend_from_slice(&frame[4 + ip.slice().len() + tcp.slice().len()..]);
+        }
+        (sent, payloads)
+    }
+
+    #[test]
+    fn fill_pipe_stops_at_the_congestion_window() {
+        let mut connection = sending(5000, 0, 3000, 1000);
+        let una = connection.send.una;
+        let (sent, payloads) = fill(&mut connection);
+        assert_eq!(sent, 1000);
+        assert_eq!(
+            payloads,
+            connection
+                .unacked
+                .lock()
+                .unwrap()
+                .data
+                .iter()
+                .copied()
+                .take(1000)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(connection.send.nxt, una.wrapping_add(1000));
+    }
+
+    #[test]
+    fn fill_pipe_stops_at_the_peer_window() {
+        let mut connection = sending(5000, 0, 700, 3000);
+        let una = connection.send.una;
+        let (sent, payloads) = fill(&mut connection);
+        assert_eq!(sent, 700);
+        assert_eq!(
+            payloads,
+            connection
+                .unacked
+                .lock()
+                .unwrap()
+                .data
+                .iter()
+                .copied()
+                .take(700)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(connection.send.nxt, una.wrapping_add(700));
+    }
+
+    #[test]
+    fn fill_pipe_counts_what_is_in_flight_against_the_window() {
+        // 300 bytes out already, so of the smaller window (the congestion window's 1000) only
+        // 700 are left, and they pick up where the ones in flight end
+        let mut connection = sending(5000, 300, 3000, 1000);
+        let una = connection.send.una;
+        let (sent, payloads) = fill(&mut connection);
+        assert_eq!(sent, 700);
+        let expected: Vec<u8> = connection
+            .unacked
+            .lock()
+            .unwrap()
+            .data
+            .iter()
+            .copied()
+            .skip(300)
+            .take(700)
+            .collect();
+        assert_eq!(payloads, expected);
+        assert_eq!(connection.send.nxt, una.wrapping_add(1000));
+
+        // And the same with the peer's window the smaller one
+        let mut connection = sending(5000, 300, 1000, 3000);
+        let (sent, payloads) = fill(&mut connection);
+        assert_eq!(sent, 700);
+        assert_eq!(payloads, expected);
+    }
+
+    #[test]
+    fn fill_pipe_sends_nothing_once_the_window_is_full() {
+        let mut connection = sending(5000, 1000, 3000, 1000);
+        assert_eq!(fill(&mut connection), (0, Vec::new()));
+        let mut connection = sending(5000, 1000, 1000, 3000);
+        assert_eq!(fill(&mut connection), (0, Vec::new()));
+    }
 }
@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+// The segments one connection sent and received, for drawing its sequence diagram (see
+// TcpConfig::trace_segments). Only the most recent `limit` segments are kept, so a long-lived
+// connection doesn't grow its trace without bound: the handshake scrolls out of a busy one, but
+// a short teaching connection fits in full.
+pub(crate) struct SegmentTrace {
+    started: Instant,
+    limit: usize,
+    segments: VecDeque<TracedSegment>,
+    // Segments that scrolled out of the trace to make room for newer ones
+    dropped: usize,
+}
+
+struct TracedSegment {
+    // Since the start of the trace
+    at: Duration,
+    // Whether we sent it, as opposed to received it
+    sent: bool,
+    flags: String,
+    seq: u32,
+    // Only meaningful with the ACK flag set
+    ack: Option<u32>,
+    len: usize,
+}
+
+// The parts of a segment's header the diagram shows. Sent segments are built from an
+// etherparse::TcpHeader and received ones parsed into a TcpHeaderSlice, so both get turned
+// into this first
+pub(crate) struct SegmentSummary {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+    pub seq: u32,
+    pub ack_number: u32,
+}
+
+impl SegmentSummary {
+    pub(crate) fn of_header(tcp: &etherparse::TcpHeader) -> Self {
+        SegmentSummary {
+            syn: tcp.syn,
+            ack: tcp.ack,
+            fin: tcp.fin,
+            rst: tcp.rst,
+            psh: tcp.psh,
+            seq: tcp.sequence_number,
+            ack_number: tcp.acknowledgment_number,
+        }
+    }
+
+    pub(crate) fn of_slice(tcp: &etherparse::TcpHeaderSlice) -> Self {
+        SegmentSummary {
+            syn: tcp.syn(),
+            ack: tcp.ack(),
+            fin: tcp.fin(),
+            rst: tcp.rst(),
+            psh: tcp.psh(),
+            seq: tcp.sequence_number(),
+            ack_number: tcp.acknowledgment_number(),
+        }
+    }
+}
+
+impl SegmentTrace {
+    pub(crate) fn new(limit: usize) -> Self {
+        SegmentTrace {
+            started: Instant::now(),
+            limit,
+            segments: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    // Adds a segment carrying `len` bytes of payload to the trace
+    pub(crate) fn record(&mut self, sent: bool, segment: SegmentSummary, len: usize) {
+        if self.segments.len() >= self.limit {
+            self.segments.pop_front();
+            self.dropped += 1;
+        }
+        let flags: Vec<&str> = [
+            (segment.syn, "SYN"),
+            (segment.fin, "FIN"),
+            (segment.rst, "RST"),
+            (segment.psh, "PSH"),
+            (segment.ack, "ACK"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect();
+        self.segments.push_back(TracedSegment {
+            at: self.started.elapsed(),
+            sent,
+            flags: flags.join(","),
+            seq: segment.seq,
+            ack: segment.ack.then_some(segment.ack_number),
+            len,
+        });
+    }
+
+    // Draws the trace as an ASCII sequence diagram, us on the left and the peer on the right,
+    // one arrow per segment in the order they went by:
+    //
+    //            192.168.0.2:8000                      192.168.0.1:55144
+    //     0.000s |<-------- SYN seq=3891667557 ---------|
+    //     0.000s |--- SYN,ACK seq=0 ack=3891667558 ---->|
+    //     0.000s |<------ ACK seq=3891667558 ack=1 -----|
+    //
+    // Sequence numbers are the absolute ones from the headers, ISNs included, so they can be
+    // matched up with a packet capture.
+    pub(crate) fn render(&self, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) -> String {
+        let labels: Vec<String> = self.segments.iter().map(label).collect();
+        // Wide enough for the longest label with a bit of arrow on either side
+        let width = labels.iter().map(String::len).max().unwrap_or(0) + 8;
+
+        let mut out = String::new();
+        let local = format!("{}:{}", local.0, local.1);
+        let remote = format!("{}:{}", remote.0, remote.1);
+        let _ = writeln!(out, "{:>10} {:<w$}{}", "", local, remote, w = width + 1);
+        if self.dropped > 0 {
+            let _ = writeln!(
+                out,
+                "{:>10} ({} earlier segments not kept)",
+                "", self.dropped
+            );
+        }
+        for (segment, label) in self.segments.iter().zip(&labels) {
+            // Centre the label on the arrow, leaving room for the arrowhead
+            let dashes = width - label.len() - 3;
+            let left = "-".repeat(dashes / 2);
+            let right = "-".repeat(dashes - dashes / 2);
+            let arrow = if segment.sent {
+                format!("|{} {} {}>|", left, label, right)
+            } else {
+                format!("|<{} {} {}|", left, label, right)
+            };
+            let _ = writeln!(out, "{:>9.3}s {}", segment.at.as_secs_f64(), arrow);
+        }
+        out
+    }
+}
+
+// What goes on a segment's arrow, e.g. "PSH,ACK seq=1 ack=1001 len=5"
+fn label(segment: &TracedSegment) -> String {
+    let mut label = format!("{} seq={}", segment.flags, segment.seq);
+    if let Some(ack) = segment.ack {
+        let _ = write!(label, " ack={}", ack);
+    }
+    if segment.len > 0 {
+        let _ = write!(label, " len={}", segment.len);
+    }
+    label
+}
@@ -0,0 +1,914 @@
+// Plays the peer against an offline interface: crafted frames go in through handle_frame, and
+// the frames the stack answers with come back out of take_sent_frames to be checked. No TUN device is involved, so
+// these run anywhere, without privileges.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown};
+use std::thread;
+use std::time::Duration;
+
+use etherparse::{
+    IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice, TcpOptionElement,
+};
+use rustcp::{
+    handle_frame, BufferMarks, Interface, Listener, State, TcpConfig, TcpStream, TimeWaitSyn,
+};
+
+const PEER: [u8; 4] = [192, 168, 0, 2];
+const LOCAL: [u8; 4] = [192, 168, 0, 1];
+const PEER_PORT: u16 = 40000;
+const LOCAL_PORT: u16 = 80;
+
+// A segment from the peer, as the TUN device would hand it over: the 4 byte flags + protocol
+// prefix, then the IPv4 packet, with `ip_options` (a multiple of 4 bytes) in its header
+fn frame_with_ip_options(ip_options: &[u8], mut tcp: TcpHeader, payload: &[u8]) -> Vec<u8> {
+    let mut ip = Ipv4Header::new(
+        tcp.header_len() + payload.len() as u16,
+        64,
+        IpNumber::Tcp as u8,
+        PEER,
+        LOCAL,
+    );
+    ip.set_options(ip_options).unwrap();
+    tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).unwrap();
+
+    let mut frame = vec![0, 0, 0x08, 0x00];
+    ip.write(&mut frame).unwrap();
+    tcp.write(&mut frame).unwrap();
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn frame(tcp: TcpHeader, payload: &[u8]) -> Vec<u8> {
+    frame_with_ip_options(&[], tcp, payload)
+}
+
+// The peer's TCP header for a segment starting at `seq`, with only the flags the caller sets
+fn segment(seq: u32) -> TcpHeader {
+    TcpHeader::new(PEER_PORT, LOCAL_PORT, seq, 64240)
+}
+
+fn syn(isn: u32) -> TcpHeader {
+    let mut tcp = segment(isn);
+    tcp.syn = true;
+    tcp
+}
+
+fn ack(seq: u32, ack: u32) -> TcpHeader {
+    let mut tcp = segment(seq);
+    tcp.ack = true;
+    tcp.acknowledgment_number = ack;
+    tcp
+}
+
+// Hands the interface a frame, and returns what the stack sent in reply
+fn exchange(iface: &Interface, frame: &[u8]) -> Vec<Vec<u8>> {
+    handle_frame(iface, frame).unwrap();
+    iface.take_sent_frames().unwrap()
+}
+
+// Takes the peer through the handshake with a listener on LOCAL_PORT, from PEER_PORT with
+// initial sequence number `isn`, and returns the sequence number the stack sends from next
+fn establish(iface: &Interface, isn: u32) -> u32 {
+    handshake(iface, syn(isn))
+}
+
+// Like establish, with the peer's SYN announcing an MSS of `mss`
+fn establish_with_mss(iface: &Interface, isn: u32, mss: u16) -> u32 {
+    let mut tcp = syn(isn);
+    tcp.set_options(&[TcpOptionElement::MaximumSegmentSize(mss)])
+        .unwrap();
+    handshake(iface, tcp)
+}
+
+// The handshake of establish, opened by the peer with `syn`
+fn handshake(iface: &Interface, syn: TcpHeader) -> u32 {
+    let replies = exchange(iface, &frame(syn.clone(), &[]));
+    let syn_ack = reply_header(&replies[0]);
+    assert!(syn_ack.syn() && syn_ack.ack());
+    let nxt = syn_ack.sequence_number().wrapping_add(1);
+    let isn = syn.sequence_number;
+    assert!(exchange(iface, &frame(ack(isn.wrapping_add(1), nxt), &[])).is_empty());
+    nxt
+}
+
+// Takes a connection from PEER_PORT, with ISN 1000, as far as TIME-WAIT: we close first, and
+// the peer acknowledges our FIN along with sending its own. Returns the stream and the sequence
+// number after our FIN
+fn into_time_wait(iface: &Interface, listener: &mut Listener) -> (TcpStream, u32) {
+    let nxt = establish(iface, 1000);
+    let stream = listener.accept().unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let sent = tick_until_sent(iface);
+    let fin = reply_header(&sent[0]);
+    assert!(fin.fin());
+    assert_eq!(fin.sequence_number(), nxt);
+
+    let mut fin_ack = ack(1001, nxt.wrapping_add(1));
+    fin_ack.fin = true;
+    let replies = exchange(iface, &frame(fin_ack, &[]));
+    assert_eq!(reply_header(&replies[0]).acknowledgment_number(), 1002);
+    assert_eq!(stream.state().unwrap(), State::TimeWait);
+    (stream, nxt.wrapping_add(1))
+}
+
+// How long TIME-WAIT has left to run on the interface's only connection, as debug_dump says
+fn time_wait_left(iface: &Interface) -> Duration {
+    let dump = iface.debug_dump();
+    let left = dump
+        .split("TIME-WAIT=")
+        .nth(1)
+        .unwrap()
+        .lines()
+        .next()
+        .unwrap();
+    Duration::from_secs_f64(left.strip_suffix('s').unwrap().parse().unwrap())
+}
+
+// SND.UNA and the count of duplicate ACKs in a row of the interface's only connection, as
+// debug_dump says
+fn una_and_dup_acks(iface: &Interface) -> (u32, u32) {
+    let dump = iface.debug_dump();
+    let una = dump
+        .split("SND.UNA=")
+        .nth(1)
+        .unwrap()
+        .split(' ')
+        .next()
+        .unwrap();
+    let dup_acks = dump
+        .split(" duplicate ACKs")
+        .next()
+        .unwrap()
+        .rsplit(' ')
+        .next()
+        .unwrap();
+    (una.parse().unwrap(), dup_acks.parse().unwrap())
+}
+
+fn rst(seq: u32) -> TcpHeader {
+    let mut tcp = segment(seq);
+    tcp.rst = true;
+    tcp
+}
+
+// The TCP header of a frame the stack sent, checking on the way that it's a TCP segment from
+// us to the peer
+fn reply_header(frame: &[u8]) -> TcpHeaderSlice<'_> {
+    reply_header_to(PEER_PORT, frame)
+}
+
+// Like reply_header, for a segment to another of the peer's ports
+fn reply_header_to(peer_port: u16, frame: &[u8]) -> TcpHeaderSlice<'_> {
+    let tcp = sent_header(frame);
+    assert_eq!(tcp.source_port(), LOCAL_PORT);
+    assert_eq!(tcp.destination_port(), peer_port);
+    tcp
+}
+
+// Like reply_header, for a segment between any of our ports and any of the peer's
+fn sent_header(frame: &[u8]) -> TcpHeaderSlice<'_> {
+    assert_eq!(&frame[..4], &[0, 0, 0x08, 0x00]);
+    let ip = Ipv4HeaderSlice::from_slice(&frame[4..]).unwrap();
+    assert_eq!(ip.source(), LOCAL);
+    assert_eq!(ip.destination(), PEER);
+    TcpHeaderSlice::from_slice(&frame[4 + ip.slice().len()..]).unwrap()
+}
+
+// The payload of a frame the stack sent
+fn sent_payload(frame: &[u8]) -> &[u8] {
+    let ip = Ipv4HeaderSlice::from_slice(&frame[4..]).unwrap();
+    let tcp = sent_header(frame);
+    &frame[4 + ip.slice().len() + tcp.slice().len()..]
+}
+
+// Runs ticks until the interface sends something, for a frame that another thread's call is
+// about to have sent (like the SYN of a connect)
+fn tick_until_sent(iface: &Interface) -> Vec<Vec<u8>> {
+    for _ in 0..1000 {
+        iface.tick().unwrap();
+        let sent = iface.take_sent_frames().unwrap();
+        if !sent.is_empty() {
+            return sent;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    panic!("nothing was sent");
+}
+
+// An interface whose address is LOCAL, for connections from us to the peer
+fn offline_at_local() -> Interface {
+    let config = TcpConfig {
+        local_addrs: vec![(Ipv4Addr::from(LOCAL), 24)],
+        ..TcpConfig::default()
+    };
+    Interface::offline(config).unwrap()
+}
+
+#[test]
+fn syn_to_a_listener_gets_a_syn_ack() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let _listener = iface.bind(LOCAL_PORT).unwrap();
+
+    let replies = exchange(&iface, &frame(syn(1000), &[]));
+    assert_eq!(replies.len(), 1);
+    let syn_ack = reply_header(&replies[0]);
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert!(!syn_ack.rst() && !syn_ack.fin());
+    assert_eq!(syn_ack.acknowledgment_number(), 1001);
+}
+
+#[test]
+fn syn_to_a_closed_port_gets_a_reset() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+
+    let replies = exchange(&iface, &frame(syn(1000), &[]));
+    assert_eq!(replies.len(), 1);
+    let rst = reply_header(&replies[0]);
+    assert!(rst.rst() && rst.ack());
+    assert_eq!(rst.acknowledgment_number(), 1001);
+}
+
+#[test]
+fn ip_options_are_skipped_to_get_to_the_segment() {
+    // Record route, with room for one address (3 bytes of option header, 4 of address), and
+    // an end of options list to pad the header to a multiple of 4; IHL 7
+    let ip_options = [7, 7, 4, 0, 0, 0, 0, 0];
+    let isn = 1000;
+    let payload = b"behind the options";
+
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+
+    // The SYN-ACK can only acknowledge the right number if the TCP header was read from where
+    // it starts, after the options
+    let replies = exchange(&iface, &frame_with_ip_options(&ip_options, syn(isn), &[]));
+    let syn_ack = reply_header(&replies[0]);
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert_eq!(syn_ack.acknowledgment_number(), isn + 1);
+    let iss = syn_ack.sequence_number();
+
+    let replies = exchange(
+        &iface,
+        &frame_with_ip_options(&ip_options, ack(isn + 1, iss + 1), &[]),
+    );
+    assert!(replies.is_empty());
+
+    // Data and a FIN together: a FIN is acknowledged right away, so the reply shows how much
+    // data was taken from the segment
+    let mut data = ack(isn + 1, iss + 1);
+    data.psh = true;
+    data.fin = true;
+    let replies = exchange(&iface, &frame_with_ip_options(&ip_options, data, payload));
+    let fin_ack = reply_header(replies.last().unwrap());
+    assert_eq!(
+        fin_ack.acknowledgment_number(),
+        isn + 1 + payload.len() as u32 + 1
+    );
+
+    // And the data is the payload itself, starting right after the TCP header
+    let mut stream = listener.accept().unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, payload);
+}
+
+#[test]
+fn syn_at_the_top_of_the_sequence_space() {
+    // The SYN takes the last sequence number there is, so the peer's data starts at 0
+    let isn = 0xFFFF_FFFF;
+
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+
+    let replies = exchange(&iface, &frame(syn(isn), &[]));
+    let syn_ack = reply_header(&replies[0]);
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert_eq!(syn_ack.acknowledgment_number(), 0);
+    let iss = syn_ack.sequence_number();
+
+    let replies = exchange(&iface, &frame(ack(0, iss.wrapping_add(1)), &[]));
+    assert!(replies.is_empty());
+
+    // Two segments of data, the second with a FIN, whose immediate ACK covers all of it
+    let mut first = ack(0, iss.wrapping_add(1));
+    first.psh = true;
+    exchange(&iface, &frame(first, b"hello "));
+    let mut second = ack(6, iss.wrapping_add(1));
+    second.psh = true;
+    second.fin = true;
+    let replies = exchange(&iface, &frame(second, b"world"));
+    let fin_ack = reply_header(replies.last().unwrap());
+    assert_eq!(fin_ack.acknowledgment_number(), 12);
+
+    let mut stream = listener.accept().unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"hello world");
+}
+
+#[test]
+fn accept_waits_for_the_handshake_to_finish() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+
+    // Two connection attempts, from different ports of the peer, of which only the second
+    // gets as far as the peer's ACK
+    let replies = exchange(&iface, &frame(syn(1000), &[]));
+    assert!(reply_header(&replies[0]).syn());
+    let mut second = syn(5000);
+    second.source_port = PEER_PORT + 1;
+    let replies = exchange(&iface, &frame(second, &[]));
+    let iss = reply_header_to(PEER_PORT + 1, &replies[0]).sequence_number();
+
+    let mut handshake_ack = ack(5001, iss.wrapping_add(1));
+    handshake_ack.source_port = PEER_PORT + 1;
+    exchange(&iface, &frame(handshake_ack, &[]));
+
+    // So the second is the one there is to accept, all the way established
+    let stream = listener.accept().unwrap();
+    assert_eq!(stream.quad().source_socket.1, PEER_PORT + 1);
+    assert_eq!(stream.state().unwrap(), State::Estab);
+}
+
+#[test]
+fn half_open_connections_go_with_their_listener() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let listener = iface.bind(LOCAL_PORT).unwrap();
+
+    let replies = exchange(&iface, &frame(syn(1000), &[]));
+    let iss = reply_header(&replies[0]).sequence_number();
+    drop(listener);
+
+    // With the listener gone, so is the connection it never got to accept: the ACK that
+    // would have completed the handshake is for a closed port now
+    let replies = exchange(&iface, &frame(ack(1001, iss.wrapping_add(1)), &[]));
+    assert_eq!(replies.len(), 1);
+    assert!(reply_header(&replies[0]).rst());
+}
+
+#[test]
+fn dropping_the_interface_wakes_up_a_blocked_accept() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+
+    let accepting = thread::spawn(move || listener.accept().map(|_| ()));
+    // Give accept the time to get to waiting; were it still on its way, it would find the
+    // interface down all the same
+    thread::sleep(Duration::from_millis(50));
+    drop(iface);
+
+    let err = accepting.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NetworkDown);
+}
+
+#[test]
+fn rst_in_the_window_gets_a_challenge_ack() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 1000);
+    let stream = listener.accept().unwrap();
+
+    // In the window, but not at RCV.NXT (1001): the peer is asked to prove it with an ACK of
+    // where we are, and the connection carries on
+    let replies = exchange(&iface, &frame(rst(1101), &[]));
+    assert_eq!(replies.len(), 1);
+    let challenge = reply_header(&replies[0]);
+    assert!(challenge.ack() && !challenge.rst());
+    assert_eq!(challenge.sequence_number(), nxt);
+    assert_eq!(challenge.acknowledgment_number(), 1001);
+    assert_eq!(stream.state().unwrap(), State::Estab);
+
+    // Exactly at RCV.NXT, the RST is taken at its word
+    assert!(exchange(&iface, &frame(rst(1001), &[])).is_empty());
+    assert_eq!(stream.state().unwrap(), State::Closed);
+}
+
+#[test]
+fn challenge_acks_are_rate_limited() {
+    let config = TcpConfig {
+        challenge_ack_limit: 3,
+        ..TcpConfig::default()
+    };
+    let iface = Interface::offline(config).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    establish(&iface, 1000);
+    let stream = listener.accept().unwrap();
+
+    // A spray of guesses, all within a second: only the first few are answered
+    let mut challenges = 0;
+    for guess in 0..10 {
+        challenges += exchange(&iface, &frame(rst(1101 + guess * 100), &[])).len();
+    }
+    assert_eq!(challenges, 3);
+    assert_eq!(stream.state().unwrap(), State::Estab);
+}
+
+#[test]
+fn filling_the_hole_acks_past_the_data_held_behind_it() {
+    // The peer's data, by sequence number: its SYN takes 49, so RCV.NXT starts at 50
+    let stream_bytes: Vec<u8> = (0..400u32).map(|seq| seq as u8).collect();
+
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 49);
+    // The frame carrying sequence numbers [from, to)
+    let data = |from: u32, to: u32| {
+        let mut tcp = ack(from, nxt);
+        tcp.psh = true;
+        frame(tcp, &stream_bytes[from as usize..to as usize])
+    };
+
+    // Two pieces ahead of RCV.NXT, with a hole before each: both are held, and each gets a
+    // duplicate ACK of 50 right away
+    for (from, to) in [(100, 200), (300, 400)] {
+        let replies = exchange(&iface, &data(from, to));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(reply_header(&replies[0]).acknowledgment_number(), 50);
+    }
+
+    // Then [50, 300), which fills the first hole and overlaps the first piece: RCV.NXT jumps
+    // over everything held, to 400, and a single ACK says so
+    let replies = exchange(&iface, &data(50, 300));
+    assert_eq!(replies.len(), 1);
+    assert_eq!(reply_header(&replies[0]).acknowledgment_number(), 400);
+
+    let mut stream = listener.accept().unwrap();
+    let mut received = vec![0; 350];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(received, &stream_bytes[50..400]);
+}
+
+#[test]
+fn syn_ack_with_the_wrong_ack_gets_a_reset() {
+    let iface = offline_at_local();
+    thread::scope(|scope| {
+        let connecting = scope.spawn(|| iface.connect((Ipv4Addr::from(PEER), PEER_PORT)));
+
+        let sent = tick_until_sent(&iface);
+        let syn = sent_header(&sent[0]);
+        assert!(syn.syn() && !syn.ack());
+        let (local_port, iss) = (syn.source_port(), syn.sequence_number());
+
+        // A SYN-ACK acknowledging one past where it should gets a RST from exactly that
+        // sequence number, and changes nothing
+        let syn_ack = |ack: u32| {
+            let mut tcp = TcpHeader::new(PEER_PORT, local_port, 7000, 64240);
+            tcp.syn = true;
+            tcp.ack = true;
+            tcp.acknowledgment_number = ack;
+            frame(tcp, &[])
+        };
+        let wrong = iss.wrapping_add(2);
+        let replies = exchange(&iface, &syn_ack(wrong));
+        assert_eq!(replies.len(), 1);
+        let reset = sent_header(&replies[0]);
+        assert!(reset.rst() && !reset.syn());
+        assert_eq!(reset.sequence_number(), wrong);
+        assert!(iface.debug_dump().contains("state: SynSent"));
+        assert!(!connecting.is_finished());
+
+        // The right one still establishes the connection
+        exchange(&iface, &syn_ack(iss.wrapping_add(1)));
+        let stream = connecting.join().unwrap().unwrap();
+        assert_eq!(stream.state().unwrap(), State::Estab);
+    });
+}
+
+#[test]
+fn connections_start_from_different_sequence_numbers() {
+    // Four connection attempts, from different ports of the peer: with a random ISS each, they
+    // can't all have been answered from the same one
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let _listener = iface.bind(LOCAL_PORT).unwrap();
+    let isss: Vec<u32> = (0..4)
+        .map(|i| {
+            let mut tcp = syn(1000);
+            tcp.source_port = PEER_PORT + i;
+            let replies = exchange(&iface, &frame(tcp, &[]));
+            reply_header_to(PEER_PORT + i, &replies[0]).sequence_number()
+        })
+        .collect();
+    assert!(isss.iter().any(|&iss| iss != isss[0]), "{:?}", isss);
+}
+
+#[test]
+fn time_wait_acks_an_old_duplicate_and_stays() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let (mut stream, nxt) = into_time_wait(&iface, &mut listener);
+
+    // Data from before the peer's FIN, delivered late: the peer is told again where we are,
+    // and the connection neither takes the data nor comes back to life
+    let mut old = ack(991, nxt);
+    old.psh = true;
+    let replies = exchange(&iface, &frame(old, b"0123456789"));
+    assert_eq!(replies.len(), 1);
+    let reply = reply_header(&replies[0]);
+    assert!(reply.ack() && !reply.syn() && !reply.rst());
+    assert_eq!(reply.sequence_number(), nxt);
+    assert_eq!(reply.acknowledgment_number(), 1002);
+    assert_eq!(stream.state().unwrap(), State::TimeWait);
+
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(received.is_empty());
+}
+
+#[test]
+fn a_retransmitted_fin_restarts_time_wait() {
+    let config = TcpConfig {
+        msl: Duration::from_secs(10),
+        ..TcpConfig::default()
+    };
+    let iface = Interface::offline(config).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let (stream, nxt) = into_time_wait(&iface, &mut listener);
+
+    thread::sleep(Duration::from_millis(100));
+    let before = time_wait_left(&iface);
+    assert!(before < Duration::from_millis(19_950), "{:?}", before);
+
+    // The peer didn't get our ACK of its FIN, so it sends the FIN again: it's acknowledged
+    // once more, and the 2 MSL start over from now
+    let mut fin = ack(1001, nxt);
+    fin.fin = true;
+    let replies = exchange(&iface, &frame(fin, &[]));
+    assert_eq!(replies.len(), 1);
+    assert_eq!(reply_header(&replies[0]).acknowledgment_number(), 1002);
+    assert!(time_wait_left(&iface) > before);
+    assert_eq!(stream.state().unwrap(), State::TimeWait);
+}
+
+#[test]
+fn a_syn_beyond_time_wait_opens_a_new_incarnation() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let (stream, nxt) = into_time_wait(&iface, &mut listener);
+    // The application is done with the old connection, so it can make way
+    drop(stream);
+
+    // Numbered past the old connection's FIN (1001): the SYN-ACK is the new connection's,
+    // sent from beyond everything the old one used
+    let replies = exchange(&iface, &frame(syn(5000), &[]));
+    assert_eq!(replies.len(), 1);
+    let syn_ack = reply_header(&replies[0]);
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert_eq!(syn_ack.acknowledgment_number(), 5001);
+    assert!(syn_ack.sequence_number().wrapping_sub(nxt) as i32 > 0);
+    let iss = syn_ack.sequence_number();
+
+    exchange(&iface, &frame(ack(5001, iss.wrapping_add(1)), &[]));
+    let stream = listener.accept().unwrap();
+    assert_eq!(stream.state().unwrap(), State::Estab);
+}
+
+#[test]
+fn a_syn_from_before_time_wait_is_acked_as_an_old_duplicate() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let (stream, nxt) = into_time_wait(&iface, &mut listener);
+    drop(stream);
+
+    // Numbered before the old connection's FIN, so it could be one of its stray SYNs: it gets
+    // the plain ACK of where the old connection left off
+    let replies = exchange(&iface, &frame(syn(500), &[]));
+    assert_eq!(replies.len(), 1);
+    let reply = reply_header(&replies[0]);
+    assert!(reply.ack() && !reply.syn() && !reply.rst());
+    assert_eq!(reply.sequence_number(), nxt);
+    assert_eq!(reply.acknowledgment_number(), 1002);
+    assert!(iface.debug_dump().contains("state: TimeWait"));
+}
+
+#[test]
+fn a_syn_from_before_time_wait_can_be_dropped() {
+    let config = TcpConfig {
+        time_wait_syn: TimeWaitSyn::Drop,
+        ..TcpConfig::default()
+    };
+    let iface = Interface::offline(config).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let (stream, _) = into_time_wait(&iface, &mut listener);
+    drop(stream);
+
+    assert!(exchange(&iface, &frame(syn(500), &[])).is_empty());
+    assert!(iface.debug_dump().contains("state: TimeWait"));
+}
+
+#[test]
+fn fin_ack_of_our_fin_goes_straight_to_time_wait() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 1000);
+    let mut stream = listener.accept().unwrap();
+
+    // Some data, then our FIN, neither of them acknowledged yet
+    stream.write_all(b"bye").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let sent = tick_until_sent(&iface);
+    let last = reply_header(sent.last().unwrap());
+    assert!(last.fin());
+    assert_eq!(stream.state().unwrap(), State::FinWait1);
+
+    // One segment from the peer acknowledges the data and our FIN, and carries its own FIN:
+    // there's no FIN-WAIT-2 to wait in, both sides are done
+    let mut fin_ack = ack(1001, nxt.wrapping_add(3 + 1));
+    fin_ack.fin = true;
+    let replies = exchange(&iface, &frame(fin_ack, &[]));
+    assert_eq!(stream.state().unwrap(), State::TimeWait);
+    assert_eq!(replies.len(), 1);
+    let reply = reply_header(&replies[0]);
+    assert_eq!(reply.sequence_number(), nxt.wrapping_add(3 + 1));
+    assert_eq!(reply.acknowledgment_number(), 1002);
+}
+
+#[test]
+fn data_to_a_listener_gets_a_reset() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let _listener = iface.bind(LOCAL_PORT).unwrap();
+
+    // With an ACK, from a connection the peer thinks it has: the RST takes its sequence
+    // number from that ACK, so the peer accepts it
+    let mut data = ack(1000, 7777);
+    data.psh = true;
+    let replies = exchange(&iface, &frame(data, b"hello"));
+    assert_eq!(replies.len(), 1);
+    let reset = reply_header(&replies[0]);
+    assert!(reset.rst() && !reset.ack());
+    assert_eq!(reset.sequence_number(), 7777);
+
+    // Without one, the RST is numbered 0 and acknowledges the whole segment instead
+    let replies = exchange(&iface, &frame(segment(1000), b"hello"));
+    assert_eq!(replies.len(), 1);
+    let reset = reply_header(&replies[0]);
+    assert!(reset.rst() && reset.ack());
+    assert_eq!(reset.sequence_number(), 0);
+    assert_eq!(reset.acknowledgment_number(), 1005);
+
+    // And no connection came of either
+    assert!(iface.debug_dump().is_empty());
+}
+
+#[test]
+fn syn_fin_to_a_listener_is_dropped() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+
+    let mut syn_fin = syn(1000);
+    syn_fin.fin = true;
+    assert!(exchange(&iface, &frame(syn_fin, &[])).is_empty());
+    // No connection was started for it...
+    assert!(iface.debug_dump().is_empty());
+
+    // ...nor queued for accept: the first connection there is to accept is the next one
+    let mut tcp = syn(5000);
+    tcp.source_port = PEER_PORT + 1;
+    let replies = exchange(&iface, &frame(tcp, &[]));
+    let iss = reply_header_to(PEER_PORT + 1, &replies[0]).sequence_number();
+    let mut handshake_ack = ack(5001, iss.wrapping_add(1));
+    handshake_ack.source_port = PEER_PORT + 1;
+    exchange(&iface, &frame(handshake_ack, &[]));
+    let stream = listener.accept().unwrap();
+    assert_eq!(stream.quad().source_socket.1, PEER_PORT + 1);
+}
+
+#[test]
+fn duplicate_acks_are_counted_and_leave_snd_una_alone() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 1000);
+    let mut stream = listener.accept().unwrap();
+
+    stream.write_all(&[0; 4000]).unwrap();
+    let sent = tick_until_sent(&iface);
+    assert!(sent.len() > 1);
+
+    // The first segment gets through, the second doesn't: every ACK from then on is for the
+    // same byte, with nothing else new about it
+    let acked = nxt.wrapping_add(sent_payload(&sent[0]).len() as u32);
+    assert!(exchange(&iface, &frame(ack(1001, acked), &[])).is_empty());
+    assert_eq!(una_and_dup_acks(&iface), (acked, 0));
+
+    for count in 1..=4 {
+        let replies = exchange(&iface, &frame(ack(1001, acked), &[]));
+        assert_eq!(una_and_dup_acks(&iface), (acked, count));
+        // The third one in a row sets off a fast retransmit, of the segment they're waiting on
+        if count == 3 {
+            assert_eq!(replies.len(), 1);
+            assert_eq!(reply_header(&replies[0]).sequence_number(), acked);
+        } else {
+            assert!(replies.is_empty());
+        }
+    }
+}
+
+#[test]
+fn a_reader_that_stops_stops_the_sender() {
+    let config = TcpConfig {
+        recv_buffer: BufferMarks { low: 1, high: 4096 },
+        // Every segment gets its ACK right away, to show the window each leaves
+        delayed_ack_bytes: Some(1),
+        ..TcpConfig::default()
+    };
+    let iface = Interface::offline(config).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 1000);
+    let mut stream = listener.accept().unwrap();
+
+    // The application doesn't read, so four segments fill the buffer, and the window we show
+    // shrinks by each of them
+    let mut windows = Vec::new();
+    for i in 0..4 {
+        let mut data = ack(1001 + i * 1024, nxt);
+        data.psh = true;
+        for reply in exchange(&iface, &frame(data, &[i as u8; 1024])) {
+            windows.push(reply_header(&reply).window_size());
+        }
+    }
+    assert_eq!(windows.len(), 4);
+    assert!(
+        windows.windows(2).all(|pair| pair[0] > pair[1]),
+        "{:?}",
+        windows
+    );
+
+    // Down to nothing: a byte more is turned away, with an ACK of just what fit
+    let replies = exchange(&iface, &frame(ack(5097, nxt), b"!"));
+    assert_eq!(replies.len(), 1);
+    let stopped = reply_header(&replies[0]);
+    assert_eq!(stopped.window_size(), 0);
+    assert_eq!(stopped.acknowledgment_number(), 5097);
+    // And stays shut while the application still doesn't read
+    iface.tick().unwrap();
+    assert!(iface.take_sent_frames().unwrap().is_empty());
+
+    // Once the application catches up, the next tick tells the peer it can go on
+    let mut received = vec![0; 4096];
+    stream.read_exact(&mut received).unwrap();
+    iface.tick().unwrap();
+    let sent = iface.take_sent_frames().unwrap();
+    assert_eq!(sent.len(), 1);
+    let update = reply_header(&sent[0]);
+    assert!(update.ack() && sent_payload(&sent[0]).is_empty());
+    assert_eq!(update.acknowledgment_number(), 5097);
+    assert_eq!(update.window_size(), 4096);
+}
+
+#[test]
+fn delayed_ack_threshold_follows_the_negotiated_mss() {
+    for mss in [536u32, 1200] {
+        let iface = Interface::offline(TcpConfig::default()).unwrap();
+        let _listener = iface.bind(LOCAL_PORT).unwrap();
+        let nxt = establish_with_mss(&iface, 1000, mss as u16);
+
+        // Half-segments: the first three (one and a half segments) wait for the timer, and the
+        // fourth makes two full segments' worth, which is acknowledged right away
+        let half = vec![0; mss as usize / 2];
+        for i in 0..4 {
+            let replies = exchange(&iface, &frame(ack(1001 + i * mss / 2, nxt), &half));
+            if i < 3 {
+                assert!(replies.is_empty(), "MSS {}: ACK after segment {}", mss, i);
+            } else {
+                assert_eq!(replies.len(), 1, "MSS {}", mss);
+                let acked = reply_header(&replies[0]).acknowledgment_number();
+                assert_eq!(acked, 1001 + 2 * mss);
+            }
+        }
+    }
+}
+
+#[test]
+fn a_tiny_mss_is_raised_to_the_floor() {
+    let floor = TcpConfig::default().min_peer_mss;
+    for mss in [1, 0] {
+        let iface = Interface::offline(TcpConfig::default()).unwrap();
+        let mut listener = iface.bind(LOCAL_PORT).unwrap();
+        let nxt = establish_with_mss(&iface, 1000, mss);
+        let mut stream = listener.accept().unwrap();
+
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        stream.write_all(&data).unwrap();
+
+        // The peer takes everything it's sent as it comes, until it has it all: every segment
+        // is the floor's size (but the last), and the transfer gets there
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            if received.len() == data.len() {
+                break;
+            }
+            for frame_sent in tick_until_sent(&iface) {
+                let payload = sent_payload(&frame_sent);
+                assert!(
+                    payload.len() <= floor,
+                    "MSS {}: {} bytes",
+                    mss,
+                    payload.len()
+                );
+                let seq = reply_header(&frame_sent).sequence_number();
+                assert_eq!(seq, nxt.wrapping_add(received.len() as u32));
+                received.extend_from_slice(payload);
+                if payload.len() < floor {
+                    assert_eq!(received.len(), data.len(), "MSS {}: short segment", mss);
+                }
+            }
+            let acked = nxt.wrapping_add(received.len() as u32);
+            exchange(&iface, &frame(ack(1001, acked), &[]));
+        }
+        assert_eq!(received, data, "MSS {}", mss);
+    }
+}
+
+#[test]
+fn an_empty_write_sends_nothing() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 1000);
+    let mut stream = listener.accept().unwrap();
+
+    assert_eq!(stream.write(&[]).unwrap(), 0);
+    iface.tick().unwrap();
+    assert!(iface.take_sent_frames().unwrap().is_empty());
+
+    // Nor does it leave anything behind for the next write to carry
+    assert_eq!(stream.write(b"data").unwrap(), 4);
+    iface.tick().unwrap();
+    let sent = iface.take_sent_frames().unwrap();
+    assert_eq!(sent.len(), 1);
+    let segment = reply_header(&sent[0]);
+    assert_eq!(sent_payload(&sent[0]), b"data");
+    assert_eq!(segment.sequence_number(), nxt);
+    assert!(segment.ack() && segment.psh());
+    assert!(!segment.syn() && !segment.fin() && !segment.rst() && !segment.urg());
+}
+
+#[test]
+fn data_on_the_handshake_ack_is_delivered() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+
+    let replies = exchange(&iface, &frame(syn(1000), &[]));
+    let iss = reply_header(&replies[0]).sequence_number();
+
+    // The ACK that completes the handshake already carries the request
+    let mut request = ack(1001, iss.wrapping_add(1));
+    request.psh = true;
+    exchange(&iface, &frame(request, b"GET /"));
+
+    let mut stream = listener.accept().unwrap();
+    assert_eq!(stream.state().unwrap(), State::Estab);
+    let mut received = [0; 5];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"GET /");
+}
+
+#[test]
+fn clones_read_and_write_side_by_side() {
+    let iface = Interface::offline(TcpConfig::default()).unwrap();
+    let mut listener = iface.bind(LOCAL_PORT).unwrap();
+    let nxt = establish(&iface, 1000);
+    let mut reader = listener.accept().unwrap();
+    let mut writer = reader.try_clone().unwrap();
+
+    let upload: Vec<u8> = (0..8000).map(|i| i as u8).collect();
+    let download: Vec<u8> = (0..8000).map(|i| (i * 7) as u8).collect();
+    thread::scope(|s| {
+        // One thread reads what the peer sends while another writes, each through its own
+        // stream, and the test plays the peer in between
+        let read = s.spawn(|| {
+            let mut received = vec![0; upload.len()];
+            reader.read_exact(&mut received).unwrap();
+            received
+        });
+        let write = s.spawn(|| {
+            for chunk in download.chunks(100) {
+                writer.write_all(chunk).unwrap();
+            }
+        });
+
+        let mut sent = Vec::new();
+        let collect = |sent: &mut Vec<u8>, frames: Vec<Vec<u8>>| {
+            for frame in frames {
+                sent.extend_from_slice(sent_payload(&frame));
+            }
+        };
+        for (i, chunk) in upload.chunks(1000).enumerate() {
+            let mut data = ack(1001 + i as u32 * 1000, nxt);
+            data.psh = true;
+            collect(&mut sent, exchange(&iface, &frame(data, chunk)));
+            iface.tick().unwrap();
+            collect(&mut sent, iface.take_sent_frames().unwrap());
+        }
+        assert_eq!(read.join().unwrap(), upload);
+
+        // What was written goes out as the peer acknowledges it
+        write.join().unwrap();
+        for _ in 0..100 {
+            iface.tick().unwrap();
+            collect(&mut sent, iface.take_sent_frames().unwrap());
+            let acked = nxt.wrapping_add(sent.len() as u32);
+            collect(&mut sent, exchange(&iface, &frame(ack(9001, acked), &[])));
+        }
+        assert_eq!(sent, download);
+    });
+}